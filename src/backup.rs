@@ -1,8 +1,10 @@
 /// This part of the crate is for interacting with the operating system's
 /// local package manager when rapture fails to install a package.
 /// It supports installing through apt, scoop, and brew.
-
 use crate::platform::Platform;
+use crate::jobs::lock_package_manager;
+use crate::error::Error;
+use crate::input::is_interactive;
 
 /// Get the name of the expected package manager for the current platform
 pub fn installer_name() -> String {
@@ -23,8 +25,10 @@ pub fn installer_name() -> String {
 }
 
 
-/// Install a package using the systems expected package manager
-pub fn install(name: String) -> Result<(), String> {
+/// Install a package using the systems expected package manager.
+/// Only one package is installed at a time, even when installs run in parallel.
+pub fn install(name: String) -> Result<(), Error> {
+    let _lock = lock_package_manager();
     match Platform::get() {
         Platform::Ubuntu => {
             apt_install(name)
@@ -42,26 +46,23 @@ pub fn install(name: String) -> Result<(), String> {
 }
 
 
-/// Install a package with apt
-fn apt_install(name: String) -> Result<(), String> {
-    match Platform::command(format!("sudo apt install {}", name)) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(format!("apt failed to install {}", name))
+/// Install a package with apt. apt asks the user to confirm the install, unless
+/// rapture isn't interactive, like with `--yes` or without a terminal. Then it
+/// can't ask, so the install goes ahead without confirmation.
+fn apt_install(name: String) -> Result<(), Error> {
+    if is_interactive() {
+        Platform::command(format!("sudo apt install {}", name))
+    } else {
+        Platform::command(format!("sudo apt install -y {}", name))
     }
 }
 
 /// Install a package with brew
-fn brew_install(name: String) -> Result<(), String> {
-    match Platform::command(format!("brew install {}", name)) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(format!("brew failed to install {}", name))
-    }
+fn brew_install(name: String) -> Result<(), Error> {
+    Platform::command(format!("brew install {}", name))
 }
 
 /// Install a package with scoop
-fn scoop_install(name: String) -> Result<(), String> {
-    match Platform::command(format!("scoop install {}", name)) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(format!("scoop failed to install {}", name))
-    }
+fn scoop_install(name: String) -> Result<(), Error> {
+    Platform::command(format!("scoop install {}", name))
 }
\ No newline at end of file
@@ -1,22 +1,183 @@
 use clap::{clap_app, crate_version, AppSettings};
 use rapture::frontend::install;
 use rapture::script::Script;
-use rapture::capture::capture;
+use rapture::error::Error;
+use rapture::manifest::Manifest;
+use rapture::jobs::set_jobs;
+use rapture::platform::{set_ignore_platform, Platform};
+use rapture::input::{set_prompter, AnswerPrompter};
+use rapture::observer::{set_observer, JsonObserver, TerminalObserver};
+use rapture::download::Downloader;
+use rapture::path::{path_to_string, PathManager};
+use std::sync::{Arc, Mutex};
+use rapture::capture::{capture_with, list, unpack, CaptureOptions, CapturedFile};
+use rapture::check::{check, Severity};
+use rapture::format::format_script;
+use rapture::env::{EnvManager, Shell};
+use serde_json::{json, Value};
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::{stderr, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 
+/// Read a rapture script from a file.
+fn read_script(file: &str) -> Result<String, Error> {
+    let mut contents = String::new();
+    match File::open(file) {
+        Ok(mut f) => match f.read_to_string(&mut contents) {
+            Ok(_) => Ok(contents),
+            Err(e) => Err(Error::io("Could not read rapture script", e))
+        },
+        Err(e) => Err(Error::io("Could not open rapture script", e))
+    }
+}
+
+/// Read a rapture script from a file, with the `rapture.toml` next to it
+/// as its manifest if there is one.
+fn open_script(file: &str) -> Result<Script, Error> {
+    let script = Script::new(read_script(file)?);
+    match Manifest::companion(file)? {
+        Some(manifest) => Ok(script.with_manifest(manifest)),
+        None => Ok(script)
+    }
+}
+
+/// Get a rapture script from a file, or download it if it is a url.
+fn load_script(location: &str) -> Result<Script, Error> {
+    if location.contains("://") {
+        Downloader::download_script(location.to_string())
+    } else {
+        open_script(location)
+    }
+}
+
+/// Whether results are printed as JSON, set with `--output json`.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// The subcommand being run, which every JSON record starts with.
+static COMMAND: Mutex<String> = Mutex::new(String::new());
+
+/// Start a JSON record for the subcommand being run.
+fn record() -> Value {
+    let command = match COMMAND.lock() {
+        Ok(command) => command.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    json!({ "command": command })
+}
+
+/// Print a JSON record to stdout, with whether the command succeeded.
+fn emit(mut record: Value, success: bool) {
+    record["success"] = json!(success);
+    println!("{}", record);
+}
+
+/// Describe the files in a captured script as JSON.
+fn files_json(files: Vec<CapturedFile>) -> Value {
+    files.into_iter()
+        .map(|file| json!({ "path": file.path, "size": file.size, "symlink": file.symlink }))
+        .collect()
+}
+
+/// Whether errors are printed with colors, set with `--color` and `NO_COLOR`.
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Decide whether to print errors with colors. By default colors are only used
+/// when stderr is a terminal and the `NO_COLOR` environment variable isn't set.
+fn set_color(choice: Option<&str>) {
+    let color = match choice {
+        Some("always") => true,
+        Some("never") => false,
+        _ => stderr().is_terminal() && std::env::var_os("NO_COLOR").map(|v| v.is_empty()).unwrap_or(true),
+    };
+    COLOR.store(color, Ordering::SeqCst);
+}
+
+/// Wrap text in an ANSI color code, if colors are enabled.
+fn paint(code: &str, text: &str) -> String {
+    if COLOR.load(Ordering::SeqCst) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Print an error and everything that caused it to stderr,
+/// and exit with the code for its kind.
+fn fail(context: &str, e: Error) -> ! {
+    fail_with(record(), context, e)
+}
+
+/// Fail like `fail`, but in JSON mode add the error to `record`
+/// and print it to stdout instead.
+fn fail_with(mut record: Value, context: &str, e: Error) -> ! {
+    if JSON.load(Ordering::SeqCst) {
+        record["context"] = json!(context);
+        record["error"] = e.to_json();
+        emit(record, false);
+        std::process::exit(e.exit_code())
+    }
+
+    eprintln!("{} {}", paint("1;31", "error:"), context);
+
+    let mut cause: Option<&dyn std::error::Error> = Some(&e);
+    while let Some(error) = cause {
+        match error.downcast_ref::<Error>() {
+            Some(Error::Script { .. }) => eprintln!("  {} {}", paint("1;34", "at"), error),
+            _ => eprintln!("  {} {}", paint("1;33", "caused by:"), error),
+        }
+        cause = error.source();
+    }
+    std::process::exit(e.exit_code())
+}
+
 fn main() {
+
     let matches = clap_app!(rapture =>
             (version: crate_version!())
             (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
             (about: "A cross platform install script library / package manager")
+            (@arg NON_INTERACTIVE: --("non-interactive") global(true) "Never wait for an answer, and answer no to every question")
+            (@arg YES: -y --yes global(true) conflicts_with[NO] "Answer yes to every question")
+            (@arg NO: --no global(true) "Answer no to every question")
+            (@arg COLOR: --color +takes_value global(true) possible_values(&["auto", "always", "never"]) "When to print errors with colors")
+            (@arg OUTPUT: --output +takes_value global(true) possible_values(&["text", "json"]) "Print results as text, or as a JSON object on stdout")
+            (after_help: "EXIT CODES:\n    0  Success\n    1  Invalid arguments\n    2  A script couldn't be understood\n    3  Reading or writing a file failed\n    4  A download failed\n    5  A command failed\n    6  A script tried to do something that isn't allowed\n    7  A dependency failed to install\n    8  The package doesn't support this platform")
             (@subcommand install =>
                 (about: "Install a rapture package")
                 (version: "0.0.1")
                 (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
                 (@arg INPUT_FILE: -f --file +takes_value "Install from an input rapture file")
                 (@arg PACKAGE: "The url for the package to install")
+                (@arg JOBS: -j --jobs +takes_value "The number of downloads and dependencies to install at the same time")
+                (@arg DRY_RUN: --("dry-run") "Print the instructions the script would run without running them")
+                (@arg IGNORE_PLATFORM: --("ignore-platform") "Install packages even on platforms they don't support")
+            )
+            (@subcommand check =>
+                (about: "Check rapture scripts for mistakes without running them")
+                (version: "0.0.1")
+                (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
+                (alias: "lint")
+                (@arg SCRIPTS: +required +multiple "The paths or urls of the rapture scripts")
+            )
+            (@subcommand fmt =>
+                (about: "Rewrite rapture scripts in the canonical style")
+                (version: "0.0.1")
+                (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
+                (@arg SCRIPTS: +required +multiple "The rapture scripts to format")
+                (@arg CHECK: --check "Don't change the scripts, only report the ones that aren't formatted")
+            )
+            (@subcommand list =>
+                (about: "List the installed packages and their binaries")
+                (version: "0.0.1")
+                (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
+            )
+            (@subcommand info =>
+                (about: "Show what a rapture script installs without running it")
+                (version: "0.0.1")
+                (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
+                (@arg SCRIPT: +required "The path or url of the rapture script")
             )
             (@subcommand capture =>
                 (about: "Capture a directory and package it into a rapture package")
@@ -24,47 +185,301 @@ fn main() {
                 (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
                 (@arg PACKAGE_NAME: +required "The name of the generated package")
                 (@arg DIRECTORY: +required "The path to the directory to capture")
+                (@arg INCLUDE: --include +takes_value +multiple number_of_values(1) "Only capture files matching this glob")
+                (@arg EXCLUDE: --exclude +takes_value +multiple number_of_values(1) "Don't capture files or directories matching this glob")
+                (@arg HIDDEN: --hidden "Capture hidden files and directories")
+                (@arg VERSION: --("package-version") +takes_value "The version of the generated package")
+                (@arg DESCRIPTION: --description +takes_value "A description of the generated package")
+                (@arg DEPENDS: --depends +takes_value +multiple number_of_values(1) "The url of a rapture script to install before the package")
+                (@arg ADD_PATH: --("add-path") +takes_value +multiple number_of_values(1) "A path in the package to add to the user's path")
+                (@arg RUN: --run +takes_value +multiple number_of_values(1) "A command to run in the package directory after installing")
+            )
+            (@subcommand unpack =>
+                (about: "Extract the files in a captured rapture script without installing it")
+                (version: "0.0.1")
+                (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
+                (alias: "extract")
+                (@arg SCRIPT: +required "The captured rapture script")
+                (@arg TARGET: required_unless("LIST") "The directory to extract the files into")
+                (@arg LIST: -l --list "List the files in the script and their sizes instead of extracting them")
+            )
+            (@subcommand env =>
+                (about: "Print the environment managed by rapture, for use with eval")
+                (version: "0.0.1")
+                (author: "Adam McDaniel <adam.mcdaniel17@gmail.com>")
+                (@arg SHELL: -s --shell +takes_value "The shell to print the environment for (sh, fish, or powershell)")
             )
     ).setting(AppSettings::ArgRequiredElseHelp)
     .get_matches();
 
+    // Global flags can be given before or after the subcommand
+    let flag = |name: &str| {
+        matches.is_present(name) || matches.subcommand().1.map(|m| m.is_present(name)).unwrap_or(false)
+    };
+    let value = |name: &str| {
+        matches.value_of(name).or_else(|| matches.subcommand().1.and_then(|m| m.value_of(name)))
+    };
+    set_color(value("COLOR"));
+
+    let json = value("OUTPUT") == Some("json");
+    JSON.store(json, Ordering::SeqCst);
+    if let Ok(mut command) = COMMAND.lock() {
+        *command = matches.subcommand_name().unwrap_or_default().to_string();
+    }
+
+    // In JSON mode stdout belongs to the record, so questions can't be answered there
+    let observer = Arc::new(JsonObserver::default());
+    if json {
+        set_observer(observer.clone());
+    } else {
+        set_observer(Arc::new(TerminalObserver));
+    }
+
+    if flag("YES") {
+        set_prompter(Arc::new(AnswerPrompter { answer: true }));
+    } else if flag("NO") || flag("NON_INTERACTIVE") || json {
+        set_prompter(Arc::new(AnswerPrompter { answer: false }));
+    }
+
 
     if let Some(install_matches) = matches.subcommand_matches("install") {
-        match install_matches.value_of("PACKAGE") {
-            Some(package) => {
-                match install(package.to_string()) {
-                    Ok(()) => {
-                        println!("Successfully installed package.")
-                    },
-                    Err(e) => {
-                        println!("There was a problem installing the package: {}", e);
-                    }
+        if let Some(jobs) = install_matches.value_of("JOBS") {
+            match jobs.parse::<usize>() {
+                Ok(jobs) if jobs > 0 => set_jobs(jobs),
+                _ => fail("Invalid arguments", Error::arguments(format!("The number of jobs must be a positive number, not '{}'", jobs)))
+            }
+        }
+
+        set_ignore_platform(install_matches.is_present("IGNORE_PLATFORM"));
+
+        if install_matches.is_present("DRY_RUN") {
+            let location = install_matches.value_of("PACKAGE").or_else(|| install_matches.value_of("INPUT_FILE"));
+            let script = match location {
+                Some(location) => load_script(location),
+                None => Err(Error::arguments("No script or url provided"))
+            };
+            let script = match script {
+                Ok(script) => script,
+                Err(e) => fail("There was a problem reading the script", e)
+            };
+
+            let steps = script.steps();
+            if json {
+                let mut plan = record();
+                plan["dry_run"] = json!(true);
+                plan["package"] = json!(script.package_name());
+                plan["steps"] = steps.iter()
+                    .map(|step| json!({ "line": step.line, "command": step.command, "args": step.args }))
+                    .collect();
+                emit(plan, true);
+            } else {
+                for step in steps {
+                    println!("{:>4}  {} {}", step.line, step.command, step.args);
                 }
-            },
+            }
+            return;
+        }
+
+        let result = match install_matches.value_of("PACKAGE") {
+            Some(package) => install(package.to_string()),
             None => {
                 match install_matches.value_of("INPUT_FILE") {
-                    Some(file) => {
-                        let mut rapture_file = File::open(file);
-                        match &mut rapture_file {
-                            Ok(f) => {
-                                let mut contents = String::new();
-                                f.read_to_string(&mut contents).unwrap();
-                                match Script::new(contents).run() {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!("There was a problem installing the package: {}", e);
-                                    }
-                                };
-                                println!("Successfully installed package.")
-                            },
-                            Err(_) => println!("Could not open rapture script")
-                        }
-                    },
-                    None => {
-                        println!("No script or url provided");
+                    Some(file) => open_script(file).and_then(|mut script| script.run()),
+                    None => Err(Error::arguments("No script or url provided"))
+                }
+            }
+        };
+
+        let mut report = record();
+        if let Value::Object(events) = observer.record() {
+            report.as_object_mut().unwrap().extend(events);
+        }
+        match result {
+            Ok(()) if json => emit(report, true),
+            Ok(()) => println!("Successfully installed package."),
+            Err(e) => {
+                let context = match e.root_cause() {
+                    Error::UnsupportedPlatform { .. } => "The package can't be installed on this platform, use --ignore-platform to install it anyway",
+                    _ => "There was a problem installing the package"
+                };
+                fail_with(report, context, e)
+            }
+        }
+    }
+
+
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        let (mut errors, mut warnings) = (0, 0);
+        let mut scripts = vec![];
+        for location in check_matches.values_of("SCRIPTS").unwrap() {
+            let script = match load_script(location) {
+                Ok(script) => script,
+                Err(e) => fail("There was a problem reading the script", e)
+            };
+            let diagnostics = check(script.contents());
+            for diagnostic in &diagnostics {
+                match diagnostic.severity {
+                    Severity::Error => errors += 1,
+                    Severity::Warning => warnings += 1,
+                }
+                if !json {
+                    let severity = match diagnostic.severity {
+                        Severity::Error => paint("1;31", "error:"),
+                        Severity::Warning => paint("1;33", "warning:"),
+                    };
+                    println!("{}:{}: {} {}", location, diagnostic.line, severity, diagnostic.message);
+                }
+            }
+            scripts.push(json!({
+                "script": location,
+                "diagnostics": diagnostics.iter().map(|d| d.to_json()).collect::<Vec<Value>>(),
+            }));
+        }
+
+        let mut report = record();
+        report["scripts"] = json!(scripts);
+        report["errors"] = json!(errors);
+        report["warnings"] = json!(warnings);
+        if errors > 0 {
+            fail_with(report, "The scripts have mistakes", Error::parse(format!("Found {} errors and {} warnings", errors, warnings)));
+        } else if json {
+            emit(report, true);
+        } else {
+            println!("Found {} errors and {} warnings", errors, warnings);
+        }
+    }
+
+
+    if let Some(fmt_matches) = matches.subcommand_matches("fmt") {
+        let only_check = fmt_matches.is_present("CHECK");
+        let mut unformatted = 0;
+        let mut scripts = vec![];
+        for file in fmt_matches.values_of("SCRIPTS").unwrap() {
+            let contents = match read_script(file) {
+                Ok(contents) => contents,
+                Err(e) => fail("There was a problem reading the script", e)
+            };
+            let formatted = format_script(&contents);
+            let changed = formatted != contents;
+            if changed {
+                unformatted += 1;
+                if !only_check {
+                    if let Err(e) = std::fs::write(file, &formatted) {
+                        fail("There was a problem formatting the script", Error::io(format!("Could not write rapture script '{}'", file), e));
                     }
                 }
+                if !json {
+                    println!("{} {}", if only_check { "Not formatted:" } else { "Formatted" }, file);
+                }
+            }
+            scripts.push(json!({ "script": file, "changed": changed }));
+        }
+
+        let mut report = record();
+        report["scripts"] = json!(scripts);
+        if only_check && unformatted > 0 {
+            fail_with(report, "The scripts aren't formatted", Error::parse(format!("{} scripts need to be formatted with `rapt fmt`", unformatted)));
+        } else if json {
+            emit(report, true);
+        }
+    }
+
+
+    if matches.subcommand_matches("list").is_some() {
+        let binaries = PathManager::linked_binaries();
+        let packages: Vec<Value> = PathManager::installed_packages()
+            .into_iter()
+            .map(|name| {
+                let bins: Vec<Value> = binaries.iter()
+                    .filter(|(_, _, package)| package.as_ref() == Some(&name))
+                    .map(|(bin, target, _)| json!({ "name": bin, "target": path_to_string(target.clone()) }))
+                    .collect();
+                json!({ "name": name, "path": PathManager::package_dir(name.clone()), "binaries": bins })
+            })
+            .collect();
+
+        if json {
+            let mut report = record();
+            report["packages"] = json!(packages);
+            emit(report, true);
+        } else {
+            for package in packages {
+                println!("{}", package["name"].as_str().unwrap_or_default());
+                for bin in package["binaries"].as_array().into_iter().flatten() {
+                    println!("    {} -> {}", bin["name"].as_str().unwrap_or_default(), bin["target"].as_str().unwrap_or_default());
+                }
+            }
+        }
+    }
+
+
+    if let Some(info_matches) = matches.subcommand_matches("info") {
+        let contents = load_script(info_matches.value_of("SCRIPT").unwrap());
+        let script = match contents {
+            Ok(script) => script,
+            Err(e) => fail("There was a problem reading the script", e)
+        };
+        let manifest = match script.manifest() {
+            Ok(manifest) => manifest,
+            Err(e) => fail("There was a problem reading the manifest", e)
+        };
+        let platforms = match script.platforms() {
+            Ok(platforms) => platforms,
+            Err(e) => fail("There was a problem reading the script", e)
+        };
+        let supported = platforms.is_empty() || platforms.iter().any(|name| name == Platform::get().os_name());
+        let steps = script.steps();
+        let declared = |command: &str| steps.iter().find(|s| s.command == command).map(|s| s.args.clone());
+        let version = declared("version").or_else(|| script.version());
+        let description = declared("description").or_else(|| script.description());
+        let depends: Vec<String> = steps.iter()
+            .filter(|s| s.command == "rapt-install")
+            .map(|s| s.args.clone())
+            .collect();
+        let files = match list(script.contents()) {
+            Ok(files) => files,
+            Err(e) => fail("There was a problem reading the script", e)
+        };
+
+        if json {
+            let mut report = record();
+            report["package"] = json!(script.package_name());
+            report["version"] = json!(version);
+            report["description"] = json!(description);
+            report["depends"] = json!(depends);
+            report["platforms"] = json!(platforms);
+            report["supported"] = json!(supported);
+            report["manifest"] = manifest.to_json();
+            report["steps"] = json!(steps.len());
+            report["files"] = files_json(files);
+            emit(report, true);
+        } else {
+            println!("package:     {}", script.package_name().unwrap_or_default());
+            if let Some(version) = version {
+                println!("version:     {}", version);
+            }
+            if let Some(description) = description {
+                println!("description: {}", description);
+            }
+            if let Some(homepage) = &manifest.homepage {
+                println!("homepage:    {}", homepage);
             }
+            if let Some(license) = &manifest.license {
+                println!("license:     {}", license);
+            }
+            if !platforms.is_empty() && supported {
+                println!("platforms:   {}", platforms.join(", "));
+            } else if !platforms.is_empty() {
+                println!("platforms:   {} (not {})", platforms.join(", "), Platform::get().os_name());
+            }
+            for dependency in depends {
+                println!("depends:     {}", dependency);
+            }
+            for binary in &manifest.binaries {
+                println!("binary:      {}", binary);
+            }
+            println!("steps:       {}", steps.len());
+            println!("files:       {} ({} bytes)", files.len(), files.iter().map(|f| f.size).sum::<u64>());
         }
     }
 
@@ -72,13 +487,78 @@ fn main() {
     if let Some(capture_matches) = matches.subcommand_matches("capture") {
         let package_name = capture_matches.value_of("PACKAGE_NAME").unwrap();
         let directory = capture_matches.value_of("DIRECTORY").unwrap();
-        match capture(package_name, directory) {
+        let values = |name| match capture_matches.values_of(name) {
+            Some(values) => values.map(|v| v.to_string()).collect(),
+            None => vec![]
+        };
+        let options = CaptureOptions {
+            include: values("INCLUDE"),
+            exclude: values("EXCLUDE"),
+            hidden: capture_matches.is_present("HIDDEN"),
+            version: capture_matches.value_of("VERSION").map(|v| v.to_string()),
+            description: capture_matches.value_of("DESCRIPTION").map(|d| d.to_string()),
+            depends: values("DEPENDS"),
+            add_paths: values("ADD_PATH"),
+            run: values("RUN"),
+        };
+        match capture_with(package_name, directory, &options) {
+            Ok(()) if json => {
+                let output = format!("{}.rapt", package_name);
+                let files = match read_script(&output).and_then(|contents| list(&contents)) {
+                    Ok(files) => files,
+                    Err(e) => fail("There was a problem reading the captured script", e)
+                };
+                let mut report = record();
+                report["output"] = json!(output);
+                report["files"] = files_json(files);
+                emit(report, true);
+            },
             Ok(()) => {
                 println!("Successfully captured directory");
             },
-            Err(e) => {
-                println!("There was a problem installing the package: {}", e);
-            }
+            Err(e) => fail("There was a problem capturing the directory", e)
+        };
+    }
+
+
+    if let Some(env_matches) = matches.subcommand_matches("env") {
+        let shell = match env_matches.value_of("SHELL") {
+            Some(name) => Shell::from_name(name),
+            None => Ok(Shell::detect())
         };
+        match shell.and_then(EnvManager::render) {
+            Ok(env) => print!("{}", env),
+            Err(e) => fail("There was a problem printing the environment", e)
+        }
+    }
+
+    if let Some(unpack_matches) = matches.subcommand_matches("unpack") {
+        let contents = match read_script(unpack_matches.value_of("SCRIPT").unwrap()) {
+            Ok(contents) => contents,
+            Err(e) => fail("There was a problem reading the script", e)
+        };
+
+        if unpack_matches.is_present("LIST") {
+            match list(&contents) {
+                Ok(files) => {
+                    let mut total = 0;
+                    for file in files {
+                        match file.symlink {
+                            Some(target) => println!("{:>12}  {} -> {}", "link", file.path, target),
+                            None => println!("{:>12}  {}", file.size, file.path),
+                        }
+                        total += file.size;
+                    }
+                    println!("{:>12}  total", total);
+                },
+                Err(e) => fail("There was a problem reading the script", e)
+            }
+        } else {
+            let target = unpack_matches.value_of("TARGET").unwrap();
+            match unpack(&contents, target) {
+                Ok(()) => println!("Successfully extracted script to {}", target),
+                Err(e) => fail("There was a problem extracting the script", e)
+            }
+        }
     }
 }
\ No newline at end of file
@@ -1,19 +1,359 @@
 use std::fmt::Display;
-use std::fs::{File, OpenOptions};
+use std::fs::{File, Metadata, OpenOptions, create_dir_all, read_link, remove_file, symlink_metadata};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use filetime::{FileTime, set_file_mtime};
+use crate::path::PathManager;
+use crate::error::Error;
+use crate::script::split_first_space;
+use crate::tokenizer::{join_continued, quote, tokenize};
+use crate::manifest::{split_header, Manifest, MANIFEST_FILE};
+use flate2::Compression;
+use flate2::read::GzEncoder;
+use flate2::write::GzDecoder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use walkdir::{WalkDir, DirEntry};
 
+/// The name of the file in the captured directory listing the files that shouldn't
+/// be captured. It uses the same syntax as `.gitignore`.
+const IGNORE_FILE_NAME: &str = ".raptignore";
+
+/// The number of compressed bytes stored on each `data` line of a `write-gzip` block.
+/// 3072 bytes encode to a 4096 character line of base64.
+const CHUNK_SIZE: usize = 3072;
+
 /// This function encodes a vector of bytes into a string of hex characters.
 /// This is used with the output of File::read_to_end to convert a file to hex.
-pub fn encode(buf: Vec<u8>) -> Result<String, String> {
+pub fn encode(buf: Vec<u8>) -> Result<String, Error> {
     Ok(*hex_d_hex::lower_hex(&buf))
 }
 
 /// This function decodes a string of hex characters into a vector of bytes.
 /// These bytes can be written to a file using File::write_all.
-pub fn decode(string: String) -> Result<Vec<u8>, String> {
-    let hex_vals = *hex_d_hex::dhex(&string);
-    Ok(hex_vals)
+/// 
+/// Anything that isn't an even number of hex digits is a parse error.
+pub fn decode(string: String) -> Result<Vec<u8>, Error> {
+    if !string.len().is_multiple_of(2) || !string.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::parse(format!("Could not decode hex '{}', expected an even number of hex digits", string)));
+    }
+    string.as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()) {
+            Some(byte) => Ok(byte),
+            None => Err(Error::parse(format!("Could not decode hex '{}'", string)))
+        })
+        .collect()
+}
+
+/// This function encodes a chunk of bytes as base64 for a `data` line.
+pub fn encode_base64(buf: &[u8]) -> String {
+    base64::encode(buf)
+}
+
+/// This function decodes the base64 on a `data` line into a chunk of bytes.
+pub fn decode_base64(string: &str) -> Result<Vec<u8>, Error> {
+    match base64::decode(string.trim()) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Err(Error::parse(format!("Could not decode base64 '{}'", string)))
+    }
+}
+
+/// This function reads the body of a `write-gzip` block and writes the decompressed
+/// contents to the file at `path`. A block looks like this:
+/// 
+/// ```text
+/// write-gzip bin/tool
+/// data H4sIAAAAAAAC/...
+/// data ...
+/// end-write
+/// ```
+/// 
+/// `lines` should start right after the `write-gzip` line. Each `data` line is decoded
+/// and decompressed as it is read, so the whole file is never held in memory.
+pub fn write_gzip<'a, I: Iterator<Item = &'a str>>(path: PathBuf, lines: &mut I) -> Result<(), Error> {
+    let file = create_file(&path)?;
+    decode_gzip(lines, file, &path.display().to_string())?;
+    Ok(())
+}
+
+/// This function decompresses the body of a `write-gzip` block into `writer`,
+/// and returns the writer once the block's `end-write` line is reached.
+/// `name` is the name of the file being written, for error messages.
+fn decode_gzip<'a, I: Iterator<Item = &'a str>, W: Write>(lines: &mut I, writer: W, name: &str) -> Result<W, Error> {
+    let mut decoder = GzDecoder::new(writer);
+    for line in lines {
+        let line = line.trim();
+        if line == "end-write" {
+            return match decoder.finish() {
+                Ok(writer) => Ok(writer),
+                Err(e) => Err(Error::io(format!("Could not decompress the contents of file '{}'", name), e))
+            };
+        }
+
+        match line.strip_prefix("data ") {
+            Some(chunk) => {
+                if let Err(e) = decoder.write_all(&decode_base64(chunk)?) {
+                    return Err(Error::io(format!("Could not write decompressed bytes to file '{}'", name), e));
+                }
+            },
+            None => return Err(Error::parse(format!("Expected a `data` line or `end-write` while writing '{}', found '{}'", name, line)))
+        }
+    }
+
+    Err(Error::parse(format!("Missing `end-write` after the contents of file '{}'", name)))
+}
+
+/// This function writes the bytes of a `write-hex` instruction to the file at `path`.
+pub fn write_hex(path: PathBuf, hex: String) -> Result<(), Error> {
+    let mut file = create_file(&path)?;
+
+    // Decode the hex string into a list of Vec<u8>.
+    // These are not UTF-8 characters!!! These are the 
+    // bytes to write directly to the opened file.
+    match decode(hex.clone()) {
+        Ok(vector) => match file.write_all(&vector) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::io(format!("Could not write decoded bytes to file '{}'", path.display()), e))
+        },
+        Err(e) => Err(e)
+    }
+}
+
+/// This function opens a file for writing, replacing its contents if it exists.
+fn create_file(path: &Path) -> Result<File, Error> {
+    match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+    {
+        Ok(f) => Ok(f),
+        Err(e) => Err(Error::io(format!("Could not open file '{}'", path.display()), e))
+    }
+}
+
+/// A file in a captured script, as listed by `rapt unpack --list`.
+pub struct CapturedFile {
+    /// The path of the file relative to the package installation directory
+    pub path: String,
+    /// The size of the file's contents in bytes
+    pub size: u64,
+    /// If the file is a symlink, the path it points to
+    pub symlink: Option<String>,
+}
+
+/// This object counts the bytes written to it, and throws them away.
+/// It's used to find the size of a file without extracting it.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// This function lists the files a captured script writes, without writing them.
+/// Only the `write-hex`, `write-gzip`, and `symlink` instructions are read,
+/// every other instruction in the script is skipped.
+pub fn list(script: &str) -> Result<Vec<CapturedFile>, Error> {
+    let mut files = vec![];
+    let mut lines = split_header(script).1.lines();
+    while let Some(line) = lines.next() {
+        let (command, args) = match split_first_space(join_continued(line, &mut lines)) {
+            Ok((c, a)) => (c, a),
+            Err(_) => continue
+        };
+
+        // Only the instructions that write files are read, shell commands are left alone
+        if !["write-gzip", "write-hex", "symlink"].contains(&command.as_str()) {
+            continue;
+        }
+        match (command.as_str(), tokenize(&args)?.as_slice()) {
+            ("write-gzip", [path]) => {
+                let counter = decode_gzip(&mut lines, ByteCounter(0), path)?;
+                files.push(CapturedFile { path: path.to_string(), size: counter.0, symlink: None });
+            },
+            ("write-hex", [path, hex]) => {
+                files.push(CapturedFile { path: path.to_string(), size: decode(hex.to_string())?.len() as u64, symlink: None });
+            },
+            ("write-hex", [path]) => {
+                files.push(CapturedFile { path: path.to_string(), size: 0, symlink: None });
+            },
+            ("symlink", [path, target]) => {
+                files.push(CapturedFile { path: path.to_string(), size: 0, symlink: Some(target.to_string()) });
+            },
+            _ => {}
+        }
+    }
+    Ok(files)
+}
+
+/// This function extracts the files a captured script writes into `target`,
+/// instead of the package installation directory. Only the `mkdir`, `write-hex`,
+/// `write-gzip`, `symlink`, `chmod`, and `mtime` instructions are run. Shell
+/// commands, dependencies, and every other instruction in the script are skipped.
+/// 
+/// Like an install, every path in the script must stay inside `target`.
+pub fn unpack(script: &str, target: &str) -> Result<(), Error> {
+    if let Err(e) = create_dir_all(target) {
+        return Err(Error::io(format!("Failed to create directory {}", target), e));
+    }
+
+    let mut lines = split_header(script).1.lines();
+    while let Some(line) = lines.next() {
+        let (command, args) = match split_first_space(join_continued(line, &mut lines)) {
+            Ok((c, a)) => (c, a),
+            Err(_) => continue
+        };
+
+        if !["mkdir", "write-gzip", "write-hex", "chmod", "mtime", "symlink"].contains(&command.as_str()) {
+            continue;
+        }
+        match (command.as_str(), tokenize(&args)?.as_slice()) {
+            ("mkdir", paths) => {
+                for path in paths {
+                    let path = PathManager::safe_join(target, path)?;
+                    if let Err(e) = create_dir_all(&path) {
+                        return Err(Error::io(format!("Failed to create directory {}", path.display()), e));
+                    }
+                }
+            },
+            ("write-gzip", [path]) => write_gzip(PathManager::safe_join(target, path)?, &mut lines)?,
+            ("write-hex", [path, hex]) => write_hex(PathManager::safe_join(target, path)?, hex.to_string())?,
+            ("write-hex", [path]) => write_hex(PathManager::safe_join(target, path)?, String::new())?,
+            ("chmod", [mode, path]) => set_mode(PathManager::safe_join(target, path)?, mode)?,
+            ("mtime", [time, path]) => set_mtime(PathManager::safe_join(target, path)?, time)?,
+            ("symlink", [path, link_target]) => {
+                let path = Path::new(path);
+                match (path.parent(), path.file_name()) {
+                    (Some(parent), Some(file_name)) => {
+                        let parent = PathManager::safe_join(target, &parent.to_string_lossy())?;
+                        make_symlink(parent.join(file_name), link_target)?;
+                    },
+                    _ => return Err(Error::parse(format!("Invalid symlink path '{}'", path.display())))
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// This function restores the unix permissions recorded by a `chmod` instruction.
+/// `mode` is the octal mode, like `755`. Other platforms don't have unix
+/// permissions, so this does nothing there.
+pub fn set_mode(path: PathBuf, mode: &str) -> Result<(), Error> {
+    let mode = match u32::from_str_radix(mode, 8) {
+        Ok(m) => m,
+        Err(_) => return Err(Error::parse(format!("Invalid file mode '{}'", mode)))
+    };
+    apply_mode(&path, mode)
+}
+
+#[cfg(unix)]
+fn apply_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    match std::fs::set_permissions(path, PermissionsExt::from_mode(mode)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io(format!("Could not set the permissions of '{}'", path.display()), e))
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_mode(_: &Path, _: u32) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Get the unix permissions of a captured file, if the platform has them.
+#[cfg(unix)]
+fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &Metadata) -> Option<u32> {
+    None
+}
+
+/// This function restores the modification time recorded by an `mtime` instruction.
+/// `seconds` is the number of seconds since the unix epoch.
+pub fn set_mtime(path: PathBuf, seconds: &str) -> Result<(), Error> {
+    let seconds = match seconds.parse::<i64>() {
+        Ok(s) => s,
+        Err(_) => return Err(Error::parse(format!("Invalid modification time '{}'", seconds)))
+    };
+
+    match set_file_mtime(&path, FileTime::from_unix_time(seconds, 0)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io(format!("Could not set the modification time of '{}'", path.display()), e))
+    }
+}
+
+/// This function recreates a symlink recorded by a `symlink` instruction.
+/// If something already exists at `link`, it is replaced.
+pub fn make_symlink(link: PathBuf, target: &str) -> Result<(), Error> {
+    if symlink_metadata(&link).is_ok() {
+        if let Err(e) = remove_file(&link) {
+            return Err(Error::io(format!("Could not replace '{}' with a symlink", link.display()), e));
+        }
+    }
+
+    match create_symlink(Path::new(target), &link) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io(format!("Could not create symlink '{}' pointing to '{}'", link.display(), target), e))
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Windows needs to know whether the target is a directory.
+// The target is relative to the directory containing the link.
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    let resolved = match link.parent() {
+        Some(parent) => parent.join(target),
+        None => target.to_path_buf()
+    };
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// This function writes a single instruction on a new line of the output rapture script.
+fn write_instruction(output: &mut File, instruction: String) -> Result<(), Error> {
+    match writeln!(output, "{}", instruction) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io(format!("Failed to append '{}' to the output rapture script", instruction), e)),
+    }
+}
+
+/// This function writes the `chmod` and `mtime` instructions that restore the
+/// permissions and modification time of a captured file or directory.
+/// `path` is the path of the entry in the package installation directory.
+fn write_metadata(output: &mut File, entry: &DirEntry, path: &str) -> Result<(), Error> {
+    let metadata = match entry.metadata() {
+        Ok(m) => m,
+        Err(e) => return Err(Error::io(format!("Could not read the metadata of '{}' while capturing", path), e.into())),
+    };
+
+    if let Some(mode) = file_mode(&metadata) {
+        write_instruction(output, format!("chmod {:o} {}", mode, quote(path)))?;
+    }
+
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    write_instruction(output, format!("mtime {} {}", mtime.unix_seconds(), quote(path)))
 }
 
 /// This function is used by the capture function to ignore hidden folders and files.
@@ -24,110 +364,459 @@ fn is_hidden(entry: &DirEntry) -> bool {
          .unwrap_or(false)
 }
 
+/// These options control which files in the directory are captured,
+/// and what else goes into the generated script.
+#[derive(Clone, Default)]
+pub struct CaptureOptions {
+    /// Glob patterns for the files to capture. If this is empty, every file is captured.
+    pub include: Vec<String>,
+    /// Glob patterns for the files and directories to leave out.
+    pub exclude: Vec<String>,
+    /// Whether to capture hidden files and directories, like `.config`.
+    pub hidden: bool,
+    /// The version of the package, written in the manifest.
+    pub version: Option<String>,
+    /// A description of the package, written in the manifest.
+    pub description: Option<String>,
+    /// Urls of rapture scripts to install before the captured files, written in the manifest.
+    pub depends: Vec<String>,
+    /// Paths in the package to add to the user's path after the files are written.
+    pub add_paths: Vec<String>,
+    /// Shell commands to run in the package directory after the files are written.
+    pub run: Vec<String>,
+}
+
+/// This object decides which entries of the captured directory make it into the script.
+/// It combines the `.raptignore` file with the options given to capture.
+/// All patterns are matched against paths relative to the captured directory.
+struct CaptureFilter {
+    root: PathBuf,
+    raptignore: Gitignore,
+    include: GlobSet,
+    exclude: GlobSet,
+    hidden: bool,
+}
+
+impl CaptureFilter {
+    /// Build a filter for the captured directory.
+    fn new(directory: &str, options: &CaptureOptions) -> Result<Self, Error> {
+        let root = PathBuf::from(directory);
+
+        let mut raptignore = GitignoreBuilder::new(&root);
+        if let Some(e) = raptignore.add(root.join(IGNORE_FILE_NAME)) {
+            // A missing .raptignore just means nothing is ignored
+            if root.join(IGNORE_FILE_NAME).exists() {
+                return Err(Error::io_message(format!("Could not read {}: {}", IGNORE_FILE_NAME, e)));
+            }
+        }
+        let raptignore = match raptignore.build() {
+            Ok(r) => r,
+            Err(e) => return Err(Error::io_message(format!("Could not read {}: {}", IGNORE_FILE_NAME, e))),
+        };
+
+        Ok(Self {
+            root,
+            raptignore,
+            include: glob_set(&options.include)?,
+            exclude: glob_set(&options.exclude)?,
+            hidden: options.hidden,
+        })
+    }
+
+    /// Get the path of an entry relative to the captured directory, with forward
+    /// slashes. This is the path the entry will have in the package installation
+    /// directory, no matter how the captured directory was given or what platform
+    /// the script is installed on.
+    fn relative_path(&self, entry: &DirEntry) -> String {
+        let relative = match entry.path().strip_prefix(&self.root) {
+            Ok(r) => r,
+            Err(_) => entry.path(),
+        };
+        let components: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        components.join("/")
+    }
+
+    /// Whether an entry should be captured. Returning false for a directory
+    /// skips everything inside it too.
+    fn keep(&self, entry: &DirEntry) -> bool {
+        // Always keep the captured directory itself
+        if entry.depth() == 0 {
+            return true;
+        }
+
+        let relative = match entry.path().strip_prefix(&self.root) {
+            Ok(r) => r,
+            Err(_) => entry.path(),
+        };
+        let is_dir = entry.file_type().is_dir();
+
+        if !self.hidden && is_hidden(entry) {
+            return false;
+        }
+        if self.raptignore.matched(relative, is_dir).is_ignore() {
+            return false;
+        }
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+        // The directory's manifest is written as the header of the script instead
+        if relative == Path::new(MANIFEST_FILE) {
+            return false;
+        }
+        // Directories are always searched for included files
+        is_dir || self.include.is_empty() || self.include.is_match(relative)
+    }
+
+    /// Whether a kept directory should be created. Without `include` patterns every
+    /// directory is, otherwise only the ones that match or contain included `files`.
+    fn has_contents(&self, dir: &DirEntry, files: &[DirEntry]) -> bool {
+        self.include.is_empty()
+            || self.include.is_match(dir.path().strip_prefix(&self.root).unwrap_or(dir.path()))
+            || files.iter().any(|file| file.path().starts_with(dir.path()))
+    }
+}
+
+/// This function builds a set of glob patterns given to capture.
+fn glob_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => builder.add(glob),
+            Err(e) => return Err(Error::parse(format!("Invalid glob pattern '{}': {}", pattern, e))),
+        };
+    }
+    match builder.build() {
+        Ok(set) => Ok(set),
+        Err(e) => Err(Error::parse(format!("Invalid glob patterns: {}", e))),
+    }
+}
+
 /// This is the heart of the capture subcommand.
 /// This function walks the over the folders and files of an entire file tree,
 /// and generates a rapture script that will recreate that exact file tree within
-/// the package installation directory.
+/// the package installation directory. Paths in the script are relative to the
+/// captured directory, so its contents end up directly in the package installation
+/// directory.
 /// 
 /// It takes two arguments: package_name, and directory.
 ///
 /// package_name is the name of the package the output rapture script will install to.
-/// Basically, it just adds `package your_package_name_here` to the top of the output script.
+/// It is written as the `name` in the manifest header at the top of the output script,
+/// along with anything else in the captured directory's own `rapture.toml`.
 /// 
 /// directory is the path to the directory to capture.
-pub fn capture<S: Display>(package_name: S, directory: S) -> Result<(), String> {
+/// 
+/// Hidden files and the files listed in the directory's `.raptignore` are not captured.
+/// Use capture_with to choose which files are captured.
+pub fn capture<S: Display>(package_name: S, directory: S) -> Result<(), Error> {
+    capture_with(package_name, directory, &CaptureOptions::default())
+}
+
+/// This function captures a directory like capture, but only captures the files
+/// allowed by `options` and the directory's `.raptignore`.
+pub fn capture_with<S: Display>(package_name: S, directory: S, options: &CaptureOptions) -> Result<(), Error> {
     // Here we create the output rapture file we will write to.
     let mut output_rapture = match OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(format!("{}.rapt", package_name))
     {
         Ok(f) => Ok(f),
-        Err(e) => Err(format!("Could not open output rapture file: {}", e.to_string())),
+        Err(e) => Err(Error::io("Could not open output rapture file", e)),
     }?;
 
-    // Write the package declaration to the top of the 
-    // file so we get access to the `write-hex` and `mkdir` commands.
-    match writeln!(output_rapture, "package {}", package_name.to_string()) {
+    write_capture(&mut output_rapture, package_name.to_string(), directory.to_string(), options)
+}
+
+/// This function writes the script capturing `directory` to `output_rapture`, see capture_with.
+fn write_capture(output_rapture: &mut File, package_name: String, directory: String, options: &CaptureOptions) -> Result<(), Error> {
+    let filter = CaptureFilter::new(&directory, options)?;
+
+    // Describe the package in a manifest at the top of the file, which also declares
+    // the package so we get access to the `write-gzip` and `mkdir` commands.
+    // The captured directory's own rapture.toml fills in anything the options don't.
+    let mut manifest = Manifest {
+        name: Some(package_name.to_string()),
+        version: options.version.clone(),
+        description: options.description.clone(),
+        dependencies: options.depends.clone(),
+        ..Manifest::default()
+    };
+    let directory_manifest = Path::new(&directory).join(MANIFEST_FILE);
+    if directory_manifest.is_file() {
+        manifest = manifest.or(Manifest::read(directory_manifest)?);
+    }
+    match write!(output_rapture, "{}", manifest.to_header()?) {
         Ok(_) => Ok(()),
-        Err(_) => Err(format!("Failed to write package declaration to file")),
+        Err(e) => Err(Error::io("Failed to write the manifest to file", e)),
     }?;
 
-    // Walk over each folder in the captured directory, and add a `mkdir` instruction
-    // to the output rapture file for that directory path.
-    for entry in WalkDir::new(directory.to_string())
+    // Walk over the captured directory once, and split what is kept into
+    // the directories and the files inside them
+    let (directories, files): (Vec<DirEntry>, Vec<DirEntry>) = WalkDir::new(&directory)
         .into_iter()
-        .filter_entry(|e| !is_hidden(e)) // filter hidden files
-        .filter_map(|e| {
-            // This is really janky for now, but I can try to mess with it later.
-            let result = e.ok();
-            match result {
-                Some(f) => {
-                    if f.file_type().is_dir() {
-                        Some(f)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            }
-        })
-    {
+        .filter_entry(|e| filter.keep(e)) // filter hidden and ignored files
+        .filter_map(|e| e.ok())
+        .filter(|e| e.depth() > 0) // the captured directory is the package directory
+        .partition(|e| e.file_type().is_dir());
+
+    // Every directory is searched for included files, but only the ones
+    // that end up with some of them are created
+    let directories: Vec<DirEntry> = directories
+        .into_iter()
+        .filter(|dir| filter.has_contents(dir, &files))
+        .collect();
+
+    // Add a `mkdir` instruction to the output rapture file for each folder.
+    // Their permissions and modification times are restored after all of
+    // their contents are written.
+    for entry in &directories {
         // Get the folder's path in string form.
-        let path = entry.path().display().to_string();
+        let path = filter.relative_path(entry);
 
         // Format the string into a mkdir instruction
-        let instruction = format!("mkdir {}", path);
+        let instruction = format!("mkdir {}", quote(&path));
 
         // Write the instruction to a new line in the output rapture script.
         match writeln!(output_rapture, "{}", instruction) {
             Ok(_) => Ok(()),
-            Err(_) => Err(format!("Failed to append {} to file {}", instruction, path)),
+            Err(e) => Err(Error::io(format!("Failed to append {} to file {}", instruction, path), e)),
         }?;
     }
 
     // Now that we have added a `mkdir` instruction for every folder,
-    // we can create `write-hex` instructions for the files.
+    // we can create `write-gzip` blocks for the files.
     // 
-    // Here we dont have to ignore directories, because reading the file contents
-    // will return an Err when we try to read the contents of a folder.
-    for entry in WalkDir::new(directory.to_string())
-        .into_iter()
-        .filter_entry(|e| !is_hidden(e)) // ignore hidden files
-        .filter_map(|e| e.ok())
-    {
+    // Each file is compressed with gzip, and the compressed bytes are written
+    // as base64 in chunks of CHUNK_SIZE bytes, one `data` line per chunk.
+    for entry in &files {
         // Get the path to the file as a string
-        let path = entry.path().display().to_string();
+        let path = filter.relative_path(entry);
+
+        // Symlinks are recorded as symlinks, instead of duplicating what they point to
+        if entry.path_is_symlink() {
+            match read_link(entry.path()) {
+                Ok(target) => write_instruction(output_rapture, format!("symlink {} {}", quote(&path), quote(&target.display().to_string().replace('\\', "/"))))?,
+                Err(e) => return Err(Error::io(format!("Could not read symlink '{}' while capturing", path), e)),
+            }
+            continue;
+        }
 
         // Open the file to read its contents for capturing
-        let mut captured_file = File::open(path.clone());
-        match &mut captured_file {
-            Ok(f) => {
-                // Make a buffer of bytes to read from the file
-                let mut contents: Vec<u8> = vec![];
-
-                // Read all the bytes in file to our buffer.
-                match f.read_to_end(&mut contents) {
-                    Ok(_) => {
-                        // If there is no error reading the file, encode the bytes
-                        // into a string of the hex representation of the bytes.
-                        let encoded_str = encode(contents)?;
-
-                        // Make a formatted string with the instruction to write
-                        let instruction = format!("write-hex {} {}", path, encoded_str);
-
-                        // Write the instruction onto a new line in the output rapture script file
-                        match writeln!(output_rapture, "{}", instruction) {
-                            Ok(_) => Ok(()),
-                            Err(_) => Err(format!("Failed to append {} to file {}", instruction, path)),
-                        }?;
-                    }
-                    Err(_) => {}
-                };
+        let captured_file = match File::open(entry.path()) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(format!("Could not open file '{}' while capturing", path), e)),
+        };
+
+        write_instruction(output_rapture, format!("write-gzip {}", quote(&path)))?;
+
+        // Compress the file as we read it, and write each chunk of compressed bytes
+        let mut encoder = GzEncoder::new(captured_file, Compression::best());
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            match encoder.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => write_instruction(output_rapture, format!("data {}", encode_base64(&chunk[..n])))?,
+                Err(e) => return Err(Error::io(format!("Could not read file '{}' while capturing", path), e)),
             }
-            Err(_) => return Err(format!("Could not open file '{}' while capturing", path)),
         }
+
+        write_instruction(output_rapture, "end-write".to_string())?;
+        write_metadata(output_rapture, entry, &path)?;
+    }
+
+    // Restore the directories' metadata last, deepest directories first,
+    // so writing their contents doesn't change it again.
+    for entry in directories.iter().rev() {
+        write_metadata(output_rapture, entry, &filter.relative_path(entry))?;
+    }
+
+    // Finally, add the post install steps
+    for path in &options.add_paths {
+        write_instruction(output_rapture, format!("add-path {}", quote(path)))?;
+    }
+    for command in &options.run {
+        write_instruction(output_rapture, format!("* {}", command))?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::temp_home;
+    use crate::script::Script;
+    use std::fs::{read, read_to_string, write};
+    use tempfile::{tempdir, TempDir};
+
+    /// Build a directory to capture, with an executable, nested and empty
+    /// directories, a symlink, a hidden file, and files the `.raptignore` leaves out.
+    fn directory() -> TempDir {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        for path in ["bin", "share/doc", "empty", "build"] {
+            create_dir_all(root.join(path)).unwrap();
+        }
+        write(root.join("bin/tool"), "#!/bin/sh\necho hi\n").unwrap();
+        set_mode(root.join("bin/tool"), "755").unwrap();
+        set_mtime(root.join("bin/tool"), "1000000000").unwrap();
+        write(root.join("share/doc/readme.txt"), "read me").unwrap();
+        set_mode(root.join("share/doc/readme.txt"), "640").unwrap();
+        write(root.join(".hidden"), "secret").unwrap();
+        write(root.join(".raptignore"), "*.log\nbuild/\n").unwrap();
+        write(root.join("debug.log"), "noise").unwrap();
+        write(root.join("build/out.o"), "object").unwrap();
+        write(root.join(MANIFEST_FILE), "description = \"A tool\"\n").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("bin/tool", root.join("link")).unwrap();
+        set_mtime(root.join("bin"), "1100000000").unwrap();
+        dir
+    }
+
+    /// Capture `dir` with `options`, and return the script.
+    fn captured(dir: &Path, options: &CaptureOptions) -> String {
+        let output = tempdir().unwrap();
+        let path = output.path().join("tool.rapt");
+        write_capture(&mut File::create(&path).unwrap(), "tool".to_string(), path_string(dir), options).unwrap();
+        read_to_string(path).unwrap()
+    }
+
+    fn path_string(path: &Path) -> String {
+        path.to_string_lossy().to_string()
+    }
+
+    fn mtime(path: &Path) -> i64 {
+        FileTime::from_last_modification_time(&symlink_metadata(path).unwrap()).unix_seconds()
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    /// Check that `target` holds what `directory` captures by default.
+    fn assert_unpacked(target: &Path) {
+        assert_eq!(read_to_string(target.join("bin/tool")).unwrap(), "#!/bin/sh\necho hi\n");
+        assert_eq!(read_to_string(target.join("share/doc/readme.txt")).unwrap(), "read me");
+        assert!(target.join("empty").is_dir());
+        assert_eq!(mtime(&target.join("bin/tool")), 1000000000);
+        assert_eq!(mtime(&target.join("bin")), 1100000000);
+        #[cfg(unix)]
+        {
+            assert_eq!(mode(&target.join("bin/tool")), 0o755);
+            assert_eq!(mode(&target.join("share/doc/readme.txt")), 0o640);
+            assert_eq!(read_link(target.join("link")).unwrap(), Path::new("bin/tool"));
+        }
+        for path in [".hidden", ".raptignore", "debug.log", "build", MANIFEST_FILE] {
+            assert!(!target.join(path).exists(), "captured '{}'", path);
+        }
+    }
+
+    #[test]
+    fn captured_files_are_unpacked_as_they_were() {
+        let source = directory();
+        let script = captured(source.path(), &CaptureOptions::default());
+        // Paths are relative to the captured directory
+        assert!(!script.contains(&path_string(source.path())), "{}", script);
+        assert!(script.contains("description = \"A tool\""));
+
+        let target = tempdir().unwrap();
+        unpack(&script, &path_string(target.path())).unwrap();
+        assert_unpacked(target.path());
+    }
+
+    #[test]
+    fn captured_files_are_installed_as_they_were() {
+        let source = directory();
+        let script = captured(source.path(), &CaptureOptions::default());
+        let (_guard, home) = temp_home();
+        Script::new(script).run().unwrap();
+        assert_unpacked(&home.path().join(".rapture").join("tool"));
+    }
+
+    #[test]
+    fn captured_files_are_listed() {
+        let source = directory();
+        let files = list(&captured(source.path(), &CaptureOptions::default())).unwrap();
+        let mut files: Vec<(String, u64, Option<String>)> = files.into_iter().map(|f| (f.path, f.size, f.symlink)).collect();
+        files.sort();
+        let mut expected = vec![
+            ("bin/tool".to_string(), 18, None),
+            ("share/doc/readme.txt".to_string(), 7, None),
+        ];
+        if cfg!(unix) {
+            expected.push(("link".to_string(), 0, Some("bin/tool".to_string())));
+        }
+        expected.sort();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn hidden_files_can_be_captured() {
+        let source = directory();
+        let options = CaptureOptions { hidden: true, ..CaptureOptions::default() };
+        let target = tempdir().unwrap();
+        unpack(&captured(source.path(), &options), &path_string(target.path())).unwrap();
+        assert_eq!(read_to_string(target.path().join(".hidden")).unwrap(), "secret");
+        assert!(target.path().join(".raptignore").exists());
+        assert!(!target.path().join("debug.log").exists());
+    }
+
+    #[test]
+    fn only_included_files_and_their_directories_are_captured() {
+        let source = directory();
+        let options = CaptureOptions { include: vec!["bin/*".to_string()], ..CaptureOptions::default() };
+        let script = captured(source.path(), &options);
+        assert!(script.contains("mkdir bin\n"), "{}", script);
+        for path in ["share", "share/doc", "empty"] {
+            assert!(!script.contains(&format!("mkdir {}\n", path)), "created '{}': {}", path, script);
+        }
+
+        let target = tempdir().unwrap();
+        unpack(&script, &path_string(target.path())).unwrap();
+        assert!(target.path().join("bin/tool").exists());
+        assert!(!target.path().join("share").exists());
+        assert!(!target.path().join("empty").exists());
+    }
+
+    #[test]
+    fn excluded_files_are_left_out() {
+        let source = directory();
+        let options = CaptureOptions { exclude: vec!["share".to_string(), "**/tool".to_string()], ..CaptureOptions::default() };
+        let target = tempdir().unwrap();
+        unpack(&captured(source.path(), &options), &path_string(target.path())).unwrap();
+        assert!(target.path().join("bin").is_dir());
+        assert!(!target.path().join("bin/tool").exists());
+        assert!(!target.path().join("share").exists());
+        assert!(target.path().join("empty").is_dir());
+    }
+
+    #[test]
+    fn old_captures_of_empty_files_are_read() {
+        let script = "package dragon\nwrite-hex empty \nwrite-hex hi 6869\n";
+        let files = list(script).unwrap();
+        assert_eq!(files.iter().map(|f| (f.path.as_str(), f.size)).collect::<Vec<_>>(), vec![("empty", 0), ("hi", 2)]);
+
+        let target = tempdir().unwrap();
+        unpack(script, &target.path().to_string_lossy()).unwrap();
+        assert_eq!(read(target.path().join("empty")).unwrap(), b"");
+        assert_eq!(read(target.path().join("hi")).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn io_errors_keep_their_cause() {
+        let target = tempdir().unwrap();
+        let file = target.path().join("file");
+        write(&file, "").unwrap();
+        match unpack("mkdir dir\n", &file.to_string_lossy()) {
+            Err(e @ Error::Io { source: Some(_), .. }) => assert!(std::error::Error::source(&e).is_some()),
+            other => panic!("{:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+/// This part of the crate checks a rapture script without running it.
+/// A script is normally only checked as it runs, so an unknown instruction on
+/// the last line is found after everything before it was installed. `check` reads
+/// every line up front and reports these as errors, along with warnings for
+/// instructions that work but are likely to cause trouble later, like downloads
+/// that aren't pinned to a hash.
+use crate::capture::{decode, decode_base64};
+use crate::download::{ArchiveOptions, BinaryOptions};
+use crate::frontend::{CloneOptions, GitRef};
+use crate::manifest::{split_header, Manifest, HEADER_FENCE};
+use crate::path::PathManager;
+use crate::platform::PLATFORM_NAMES;
+use crate::script::{line_number, takes_words, usage, Script};
+use crate::tokenizer::{join_continued, tokenize};
+use serde_json::{json, Value};
+use std::fmt::{self, Display, Formatter};
+use std::path::{Component, Path};
+
+/// Every instruction a script can use.
+const INSTRUCTIONS: [&str; 26] = [
+    "package", "version", "description", "platforms", "git-clone", "fetch-archive", "fetch-binary",
+    "rapt-install", "backend-install", "write-hex", "write-gzip", "chmod", "mtime",
+    "symlink", "mkdir", "echo", "add-path", "set-env", "link-bin", "#",
+    "WINDOWS", "MACOS", "UBUNTU", "LINUX", "UNKNOWN", "*",
+];
+
+/// The instructions that work inside the package installation directory,
+/// so they can only be used after the package declaration.
+const PACKAGE_INSTRUCTIONS: [&str; 11] = [
+    "git-clone", "fetch-archive", "fetch-binary", "write-hex", "write-gzip",
+    "chmod", "mtime", "symlink", "mkdir", "add-path", "link-bin",
+];
+
+/// The instructions that run a shell command, one for each platform.
+const PLATFORM_INSTRUCTIONS: [&str; 6] = ["WINDOWS", "MACOS", "UBUNTU", "LINUX", "UNKNOWN", "*"];
+
+/// How serious a problem found by `check` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The instruction works, but probably not the way the author wants it to
+    Warning,
+    /// The instruction fails or is skipped when the script runs
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found on a line of a script.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The line of the script the problem is on, starting at 1
+    pub line: usize,
+    /// The instruction on the line, like `git-clone`
+    pub instruction: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// This function describes the problem as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "severity": self.severity.to_string(),
+            "line": self.line,
+            "instruction": self.instruction,
+            "message": self.message,
+        })
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.severity, self.message)
+    }
+}
+
+/// Collects the problems found in a script, and what is known
+/// about the script at the line being checked.
+struct Checker {
+    diagnostics: Vec<Diagnostic>,
+    package: bool,
+    /// The name of the package in the manifest, if it has one
+    manifest_name: Option<String>,
+    /// Whether the supported platforms were declared yet
+    platforms: bool,
+    /// Every platform the package supports, declared anywhere in the script
+    supported: Vec<String>,
+    line: usize,
+    instruction: String,
+}
+
+impl Checker {
+    fn report<S: ToString>(&mut self, severity: Severity, message: S) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            line: self.line,
+            instruction: self.instruction.clone(),
+            message: message.to_string(),
+        });
+    }
+
+    fn error<S: ToString>(&mut self, message: S) {
+        self.report(Severity::Error, message)
+    }
+
+    fn warning<S: ToString>(&mut self, message: S) {
+        self.report(Severity::Warning, message)
+    }
+
+    /// Report an instruction that was given the wrong number of arguments.
+    fn usage(&mut self, command: &str) {
+        self.error(format!("Expected '{}'", usage(command).unwrap_or(command)));
+    }
+
+    /// Report a path that `PathManager::safe_join` will refuse when the script runs.
+    fn path(&mut self, path: &str) {
+        let safe = Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !safe {
+            self.error(format!("The path '{}' must be relative to the package and cannot contain '..'", path));
+        }
+    }
+
+    /// Report a url that isn't downloaded over https.
+    fn url(&mut self, url: &str) {
+        if url.starts_with("http://") {
+            self.warning(format!("'{}' is downloaded without https", url));
+        }
+    }
+
+    /// Report shell commands that fail or do something twice when a package is installed again.
+    fn shell(&mut self, command: &str) {
+        for part in command.split([';', '&', '|']) {
+            let words: Vec<&str> = part.split_whitespace().collect();
+            match words.as_slice() {
+                ["mkdir", args @ ..] if !args.iter().any(|a| a.starts_with('-') && a.contains('p')) => {
+                    self.warning("'mkdir' fails if the directory already exists, use 'mkdir -p' or the mkdir instruction")
+                },
+                ["ln", args @ ..] if !args.iter().any(|a| a.starts_with('-') && a.contains('f')) => {
+                    self.warning("'ln' fails if the link already exists, use 'ln -f' or the symlink and link-bin instructions")
+                },
+                ["git", "clone", ..] => {
+                    self.warning("'git clone' fails if the repository was already cloned, use the git-clone instruction")
+                },
+                _ => {}
+            }
+        }
+        if command.contains(">>") {
+            self.warning("'>>' appends again every time the package is installed");
+        }
+    }
+
+    /// Check the name of a platform the package supports.
+    fn platform(&mut self, name: &str) {
+        if !PLATFORM_NAMES.contains(&name) {
+            self.error(format!("Unknown platform '{}', expected one of {}", name, PLATFORM_NAMES.join(", ")));
+        }
+    }
+
+    /// Check the manifest in the header of the script.
+    fn manifest(&mut self, manifest: &Manifest) {
+        if let Some(name) = &manifest.name {
+            if let Err(e) = PathManager::check_package_name(name) {
+                self.error(e);
+            }
+            self.package = true;
+            self.manifest_name = manifest.name.clone();
+        } else if !manifest.binaries.is_empty() {
+            self.error("The manifest lists binaries, but doesn't name the package");
+        }
+        for url in &manifest.dependencies {
+            self.url(url);
+        }
+        self.platforms = !manifest.platforms.is_empty();
+        for platform in &manifest.platforms {
+            self.platform(platform);
+        }
+        for path in &manifest.binaries {
+            self.path(path);
+        }
+    }
+
+    /// Report a shell command for one platform that the package doesn't support,
+    /// because it never runs.
+    fn unreachable(&mut self, command: &str) {
+        let platform = match command {
+            "WINDOWS" => "windows",
+            "MACOS" => "macos",
+            "UBUNTU" | "LINUX" | "UNKNOWN" => "linux",
+            _ => return,
+        };
+        if !self.supported.is_empty() && !self.supported.iter().any(|name| name == platform) {
+            self.warning(format!("{} lines never run, the package only supports {}", command, self.supported.join(", ")));
+        }
+    }
+
+    /// Check a single instruction and its arguments.
+    fn instruction(&mut self, command: &str, args: &str) {
+        if PACKAGE_INSTRUCTIONS.contains(&command) && !self.package {
+            self.error(format!("'{}' can only be used after the package is declared with `package PACKAGE_NAME`", command));
+        }
+
+        // Shell commands and text are kept as they are, everything else is split into words
+        let words = if !takes_words(command) {
+            vec![]
+        } else {
+            match tokenize(args) {
+                Ok(words) => words,
+                Err(e) => return self.error(e),
+            }
+        };
+        let words: Vec<&str> = words.iter().map(|word| word.as_str()).collect();
+        self.unreachable(command);
+
+        match command {
+            "package" => {
+                let mismatch = match (words.as_slice(), &self.manifest_name) {
+                    ([name], Some(declared)) if name != declared => Some(declared.clone()),
+                    _ => None
+                };
+                if words.len() != 1 {
+                    self.usage(command);
+                } else if let Err(e) = PathManager::check_package_name(words[0]) {
+                    self.error(e);
+                } else if let Some(declared) = mismatch {
+                    self.error(format!("The manifest names the package '{}', but this declares '{}'", declared, words[0]));
+                } else if self.package {
+                    self.warning("The package is declared more than once");
+                }
+                self.package = true;
+            },
+            "platforms" => {
+                if self.platforms {
+                    self.warning("The platforms are declared more than once, the package supports all of them");
+                }
+                for name in &words {
+                    self.platform(name);
+                }
+                self.platforms = true;
+            },
+            "git-clone" => match CloneOptions::parse(args) {
+                Ok((url, options)) => {
+                    self.url(&url);
+                    if let Some(into) = &options.into {
+                        self.path(into);
+                    }
+                    match options.reference {
+                        Some(GitRef::Tag(_)) | Some(GitRef::Rev(_)) => {},
+                        _ => self.warning(format!("'{}' isn't pinned, add tag= or rev= to always install the same code", url)),
+                    }
+                },
+                Err(e) => self.error(e),
+            },
+            "fetch-archive" => match ArchiveOptions::parse(args) {
+                Ok((url, options)) => {
+                    self.url(&url);
+                    if let Some(into) = &options.into {
+                        self.path(into);
+                    }
+                    if options.sha256.is_none() {
+                        self.warning(format!("'{}' isn't pinned, add sha256= to make sure it doesn't change", url));
+                    }
+                },
+                Err(e) => self.error(e),
+            },
+            "fetch-binary" => match BinaryOptions::parse(args) {
+                Ok((template, options)) => {
+                    self.url(&template);
+                    if let Some(into) = &options.archive.into {
+                        self.path(into);
+                    }
+                    let pinned = options.archive.sha256.is_some() || options.overrides.iter().any(|(key, _, _)| key == "sha256");
+                    if !pinned {
+                        self.warning(format!("'{}' isn't pinned, add sha256= to make sure it doesn't change", template));
+                    }
+                },
+                Err(e) => self.error(e),
+            },
+            "rapt-install" => match words.as_slice() {
+                [url] => self.url(url),
+                _ => self.usage(command),
+            },
+            "write-hex" => match words.as_slice() {
+                [path, hex] => {
+                    self.path(path);
+                    if let Err(e) = decode(hex.to_string()) {
+                        self.error(e);
+                    }
+                },
+                // Older captures wrote empty files without any hex
+                [path] => self.path(path),
+                _ => self.usage(command),
+            },
+            "chmod" | "mtime" => match words.as_slice() {
+                [value, path] => {
+                    self.path(path);
+                    if command == "chmod" && u32::from_str_radix(value, 8).is_err() {
+                        self.error(format!("Invalid file mode '{}'", value));
+                    }
+                    if command == "mtime" && value.parse::<i64>().is_err() {
+                        self.error(format!("Invalid modification time '{}'", value));
+                    }
+                },
+                _ => self.usage(command),
+            },
+            "symlink" => match words.as_slice() {
+                [path, _] => self.path(Path::new(path).parent().and_then(|p| p.to_str()).unwrap_or_default()),
+                _ => self.usage(command),
+            },
+            "set-env" if words.len() < 2 => self.usage(command),
+            "version" | "write-gzip" if words.len() != 1 => self.usage(command),
+            "write-gzip" | "mkdir" | "add-path" => {
+                for path in &words {
+                    self.path(path);
+                }
+            },
+            "link-bin" => match words.as_slice() {
+                [path] => self.path(path),
+                [path, "as", name] => {
+                    self.path(path);
+                    if let Err(e) = PathManager::check_link_name(name) {
+                        self.error(e);
+                    }
+                },
+                _ => self.usage(command),
+            },
+            "UNKNOWN" => {
+                self.warning("UNKNOWN lines only run on operating systems rapture doesn't recognize");
+                self.shell(args);
+            },
+            "LINUX" => {
+                self.warning("LINUX lines only run on Ubuntu and Debian, write UBUNTU to make that clear or use '*'");
+                self.shell(args);
+            },
+            command if PLATFORM_INSTRUCTIONS.contains(&command) => self.shell(args),
+            _ => {}
+        }
+    }
+}
+
+/// This function checks every line of a script without running it,
+/// and returns the problems it found in the order of their lines.
+pub fn check(script: &str) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        diagnostics: vec![],
+        package: false,
+        manifest_name: None,
+        platforms: false,
+        supported: Script::new(script).platforms().unwrap_or_default(),
+        line: 0,
+        instruction: String::new(),
+    };
+
+    let (header, body) = split_header(script);
+    checker.instruction = HEADER_FENCE.to_string();
+    if let Some(toml) = header {
+        checker.line = line_number(script, toml) - 1;
+        match Manifest::parse(toml) {
+            Ok(manifest) => checker.manifest(&manifest),
+            Err(e) => checker.error(e),
+        }
+    } else if script.trim_start().lines().next().map(str::trim) == Some(HEADER_FENCE) {
+        checker.line = line_number(script, script.trim_start());
+        checker.error(format!("The manifest header is missing its closing '{}'", HEADER_FENCE));
+        // The rest of the script can't be told apart from the manifest
+        return checker.diagnostics;
+    }
+
+    let mut lines = body.lines();
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        checker.line = line_number(script, line);
+        // A line ending with a backslash continues on the next line
+        let text = join_continued(line, &mut lines);
+        let line = text.trim();
+
+        let (command, args) = match line.split_once([' ', '\t']) {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+        checker.instruction = command.to_string();
+
+        if command.starts_with('#') {
+            // `#comment` is still a comment, `rapt fmt` writes it as `# comment`
+            if command != "#" {
+                checker.warning(format!("Comments need a space after '#', like '# {}'", line.trim_start_matches('#')));
+            }
+            continue;
+        }
+
+        if !INSTRUCTIONS.contains(&command) {
+            let upper = command.to_uppercase();
+            if PLATFORM_INSTRUCTIONS.contains(&upper.as_str()) {
+                checker.error(format!("Unknown instruction '{}', platforms are written in capitals like '{}'", command, upper));
+            } else {
+                checker.error(format!("Unknown instruction '{}'", command));
+            }
+        } else if args.is_empty() && PLATFORM_INSTRUCTIONS.contains(&command) {
+            checker.error(format!("'{}' expects a shell command to run", command));
+        } else if args.is_empty() && !["echo", "description"].contains(&command) {
+            checker.usage(command);
+        } else {
+            checker.instruction(command, args);
+        }
+
+        // The compressed contents of a file follow `write-gzip` until `end-write`
+        if command == "write-gzip" {
+            let mut ended = false;
+            let mut reported = false;
+            for data in lines.by_ref() {
+                if data.trim() == "end-write" {
+                    ended = true;
+                    break;
+                }
+                if reported {
+                    continue;
+                }
+                match data.trim().strip_prefix("data ") {
+                    Some(chunk) => if let Err(e) = decode_base64(chunk) {
+                        checker.line = line_number(script, data);
+                        checker.error(e);
+                        reported = true;
+                    },
+                    None => {
+                        checker.line = line_number(script, data);
+                        checker.error(format!("Expected a 'data' line or 'end-write' in 'write-gzip', found '{}'", data.trim()));
+                        reported = true;
+                    }
+                }
+            }
+            if !ended {
+                checker.error("'write-gzip' is missing its 'end-write' line");
+            }
+        }
+    }
+    checker.diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(script: &str) -> Vec<(usize, Severity, String)> {
+        check(script).into_iter().map(|d| (d.line, d.severity, d.message)).collect()
+    }
+
+    fn reports(script: &str, line: usize, severity: Severity, text: &str) -> bool {
+        messages(script).iter().any(|(l, s, m)| *l == line && *s == severity && m.contains(text))
+    }
+
+    #[test]
+    fn bare_comments_are_warned_about() {
+        let script = "package dragon\n#comment\n# comment\n";
+        assert!(reports(script, 2, Severity::Warning, "space after '#'"));
+        assert_eq!(messages(script).len(), 1);
+    }
+
+    #[test]
+    fn hex_data_is_checked() {
+        let script = "package dragon\nwrite-hex a 6869\nwrite-hex b 686\nwrite-hex c zz\n";
+        assert!(!reports(script, 2, Severity::Error, "hex"));
+        assert!(reports(script, 3, Severity::Error, "hex"));
+        assert!(reports(script, 4, Severity::Error, "hex"));
+    }
+
+    #[test]
+    fn modification_times_are_checked_like_they_are_installed() {
+        let script = "package dragon\nmtime 9223372036854775807 a\nmtime -1 a\nmtime 9223372036854775808 a\nmtime soon a\n";
+        assert!(!reports(script, 2, Severity::Error, "modification time"));
+        assert!(!reports(script, 3, Severity::Error, "modification time"));
+        assert!(reports(script, 4, Severity::Error, "modification time"));
+        assert!(reports(script, 5, Severity::Error, "modification time"));
+    }
+
+    #[test]
+    fn empty_hex_files_are_allowed() {
+        assert!(messages("package dragon\nwrite-hex empty \n").is_empty());
+    }
+
+    #[test]
+    fn link_names_are_checked() {
+        let script = "package dragon\nlink-bin bin/tool as tool\nlink-bin bin/tool as ../tool\n";
+        assert!(!reports(script, 2, Severity::Error, "name of a binary"));
+        assert!(reports(script, 3, Severity::Error, "name of a binary"));
+    }
+
+    #[test]
+    fn package_names_are_checked() {
+        assert!(reports("package ..\n", 1, Severity::Error, "package name"));
+        assert!(!reports("package dragon\n", 1, Severity::Error, "package name"));
+    }
+
+    #[test]
+    fn lines_for_unsupported_platforms_are_warned_about() {
+        let script = "package dragon\nplatforms linux\nWINDOWS echo hi\nUBUNTU echo hi\n";
+        assert!(reports(script, 3, Severity::Warning, "only supports"));
+        assert!(!reports(script, 4, Severity::Warning, "only supports"));
+    }
+
+    #[test]
+    fn linux_lines_are_warned_about() {
+        assert!(reports("package dragon\nLINUX echo hi\n", 2, Severity::Warning, "Ubuntu and Debian"));
+    }
+}
@@ -1,9 +1,202 @@
 use crate::platform::Platform;
 use crate::path::{PathManager, path_to_string};
 use crate::script::Script;
-use std::path::PathBuf;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::capture::set_mode;
+use std::path::{Component, Path, PathBuf};
+use std::fs::{File, create_dir_all, hard_link, remove_file, symlink_metadata};
+use std::io::{self, prelude::*, BufReader};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType};
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use crate::observer::observer;
+use crate::error::Error;
+use crate::tokenizer::tokenize;
+
+/// The number of scripts and archives downloaded so far, used to give each download its own file.
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// The archive formats that `fetch-archive` can extract.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guess the format of an archive from the file extension in its url.
+    pub fn from_url<S: ToString>(url: S) -> Option<Self> {
+        // Ignore query strings and fragments, like `?raw=true`
+        let url = url.to_string().to_lowercase();
+        let path = url.split(['?', '#']).next().unwrap_or("");
+
+        let formats = [
+            (".tar.gz", ArchiveFormat::TarGz),
+            (".tgz", ArchiveFormat::TarGz),
+            (".tar.xz", ArchiveFormat::TarXz),
+            (".txz", ArchiveFormat::TarXz),
+            (".tar.zst", ArchiveFormat::TarZst),
+            (".tzst", ArchiveFormat::TarZst),
+            (".tar", ArchiveFormat::Tar),
+            (".zip", ArchiveFormat::Zip),
+        ];
+        formats.iter().find(|(ext, _)| path.ends_with(ext)).map(|(_, format)| *format)
+    }
+}
+
+/// The optional arguments of `fetch-archive`:
+/// `fetch-archive <url> [into <dir>] [sha256=<hash>] [strip=<N>]`
+#[derive(Clone, Default)]
+pub struct ArchiveOptions {
+    /// The directory in the package to extract into. Defaults to the package directory itself.
+    pub into: Option<String>,
+    /// The expected sha256 hash of the downloaded archive, in hex.
+    pub sha256: Option<String>,
+    /// The number of leading path components to remove from each file in the archive,
+    /// like `tar --strip-components`.
+    pub strip: usize,
+}
+
+impl ArchiveOptions {
+    /// Parse the arguments of a `fetch-archive` instruction into the url and its options.
+    pub fn parse<S: ToString>(args: S) -> Result<(String, Self), Error> {
+        let words = tokenize(&args.to_string())?;
+        let mut words = words.iter().map(|word| word.as_str());
+        let url = match words.next() {
+            Some(url) => url.to_string(),
+            None => return Err(Error::parse("Expected a url to download the archive from"))
+        };
+
+        let mut options = Self::default();
+        while let Some(word) = words.next() {
+            if !options.parse_option(word, &mut words)? {
+                return Err(Error::parse(format!("Unrecognized archive option '{}'", word)));
+            }
+        }
+        Ok((url, options))
+    }
+
+    /// Parse a single option, taking any value that follows it from `words`.
+    /// Returns false if `word` isn't an archive option.
+    fn parse_option<'a, I: Iterator<Item = &'a str>>(&mut self, word: &str, words: &mut I) -> Result<bool, Error> {
+        if word == "into" {
+            match words.next() {
+                Some(dir) => self.into = Some(dir.to_string()),
+                None => return Err(Error::parse("Expected a directory after 'into'"))
+            }
+        } else if let Some(hash) = word.strip_prefix("sha256=") {
+            self.sha256 = Some(hash.to_lowercase());
+        } else if let Some(count) = word.strip_prefix("strip=") {
+            match count.parse::<usize>() {
+                Ok(n) => self.strip = n,
+                Err(_) => return Err(Error::parse(format!("Invalid number of components to strip '{}'", count)))
+            }
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// The optional arguments of `fetch-binary`:
+/// `fetch-binary <url template> [into <dir>] [as <name>] [sha256=<hash>] [strip=<N>] [bin=<path>]... [<key>.<target>=<value>]...`
+/// 
+/// The url template can contain `{os}`, `{arch}`, and `{ext}` placeholders. By default they
+/// are replaced with `linux`, `macos`, or `windows`, the cpu architecture like `x86_64`, and
+/// `zip` on windows or `tar.gz` everywhere else. Each of them, and the `url` and `sha256`
+/// themselves, can be overridden for a target. A target is an os, like `macos`, an
+/// architecture, like `aarch64`, or both, like `macos-aarch64`. For example:
+/// 
+/// ```text
+/// fetch-binary https://example.com/tool-{os}-{arch}.{ext} os.macos=darwin arch.x86_64=amd64 sha256.linux-x86_64=...
+/// ```
+#[derive(Clone, Default)]
+pub struct BinaryOptions {
+    /// The options shared with `fetch-archive`, used when the url is an archive
+    pub archive: ArchiveOptions,
+    /// The name to save the binary as, when the url isn't an archive
+    pub name: Option<String>,
+    /// Files in an extracted archive to mark as executable
+    pub bins: Vec<String>,
+    /// The per-target overrides, as (key, target, value)
+    pub overrides: Vec<(String, String, String)>,
+}
+
+impl BinaryOptions {
+    /// Parse the arguments of a `fetch-binary` instruction into the url template and its options.
+    pub fn parse<S: ToString>(args: S) -> Result<(String, Self), Error> {
+        let words = tokenize(&args.to_string())?;
+        let mut words = words.iter().map(|word| word.as_str());
+        let template = match words.next() {
+            Some(template) => template.to_string(),
+            None => return Err(Error::parse("Expected a url to download the binary from"))
+        };
+
+        let mut options = Self::default();
+        while let Some(word) = words.next() {
+            if options.archive.parse_option(word, &mut words)? {
+                continue;
+            }
+
+            if word == "as" {
+                match words.next() {
+                    Some(name) => options.name = Some(name.to_string()),
+                    None => return Err(Error::parse("Expected a name after 'as'"))
+                }
+            } else if let Some(path) = word.strip_prefix("bin=") {
+                options.bins.push(path.to_string());
+            } else {
+                // Everything else must be an override, like `os.macos=darwin`
+                let (key, value) = match word.find('=') {
+                    Some(i) => (&word[..i], &word[i + 1..]),
+                    None => return Err(Error::parse(format!("Unrecognized binary option '{}'", word)))
+                };
+                match key.find('.') {
+                    Some(i) if ["os", "arch", "ext", "url", "sha256"].contains(&&key[..i]) => {
+                        options.overrides.push((key[..i].to_string(), key[i + 1..].to_string(), value.to_string()))
+                    },
+                    _ => return Err(Error::parse(format!("Unrecognized binary option '{}'", word)))
+                }
+            }
+        }
+        Ok((template, options))
+    }
+
+    /// Get the value of an override for a platform. An override for the os and
+    /// architecture wins over an override for just the os, which wins over an
+    /// override for just the architecture.
+    fn lookup(&self, key: &str, os: &str, arch: &str) -> Option<String> {
+        let os_arch = format!("{}-{}", os, arch);
+        let find = |target: &str| {
+            self.overrides
+                .iter()
+                .find(|(k, t, _)| k == key && t == target)
+                .map(|(_, _, value)| value.clone())
+        };
+        find(&os_arch).or_else(|| find(os)).or_else(|| find(arch))
+    }
+
+    /// Get the url and expected sha256 hash of the binary for a platform.
+    pub fn resolve(&self, template: &str, os: &str, arch: &str) -> (String, Option<String>) {
+        let default_ext = if os == "windows" { "zip" } else { "tar.gz" };
+        let template = self.lookup("url", os, arch).unwrap_or_else(|| template.to_string());
+
+        let url = template
+            .replace("{os}", &self.lookup("os", os, arch).unwrap_or_else(|| os.to_string()))
+            .replace("{arch}", &self.lookup("arch", os, arch).unwrap_or_else(|| arch.to_string()))
+            .replace("{ext}", &self.lookup("ext", os, arch).unwrap_or_else(|| default_ext.to_string()));
+        let sha256 = self.lookup("sha256", os, arch).or_else(|| self.archive.sha256.clone());
+        (url, sha256.map(|s| s.to_lowercase()))
+    }
+}
 
 
 /// This is an empty struct similar to PathManager, but exclusive to downloading
@@ -18,17 +211,22 @@ impl Downloader {
     /// downloading, we read the script from the output file and return it.
     /// 
     /// See script::Script for more information on how the script object works.
-    pub fn download_script(url: String) -> Result<Script, String> {
+    pub fn download_script(url: String) -> Result<Script, Error> {
         // Get the install directory, `~/.rapture/`
         PathManager::make_install_dir()?;
 
-        // Here we push `rapture_download.txt` onto the path,
-        // so `download_file` will download to `~/.rapture/rapture_download.txt`.
+        // Here we push `rapture_download-N.txt` onto the path,
+        // so `download_file` will download to `~/.rapture/rapture_download-N.txt`.
         // I chose this file location because it doesnt get in the way of packages,
-        // but it stays in the rapture install directory.
+        // but it stays in the rapture install directory. Each download gets its own
+        // number, so scripts downloaded at the same time don't overwrite each other.
         let mut download_pathbuf = PathBuf::new();
         download_pathbuf.push(PathManager::install_dir());
-        download_pathbuf.push("rapture_download.txt");
+        download_pathbuf.push(format!(
+            "rapture_download-{}-{}.txt",
+            std::process::id(),
+            DOWNLOADS.fetch_add(1, Ordering::SeqCst)
+        ));
         let download_path = path_to_string(download_pathbuf);
 
         // Download the file
@@ -36,31 +234,535 @@ impl Downloader {
 
         // Open the file for reading, we want to create a script object
         // with the contents of the downloaded script.
-        let mut downloaded_file = File::open(download_path);
-        match &mut downloaded_file {
-            Ok(f) => {
+        let downloaded_file = File::open(&download_path);
+        let result = match downloaded_file {
+            Ok(mut f) => {
                 // Success!
                 // Read the file as valid UTF-8
                 let mut contents = String::new();
-                f.read_to_string(&mut contents).unwrap();
-                Ok(Script::new(contents))
+                match f.read_to_string(&mut contents) {
+                    Ok(_) => Ok(Script::new(contents)),
+                    Err(e) => Err(Error::io("Could not read downloaded script as UTF-8", e))
+                }
             },
             // Reading the file returned an error
-            Err(_) => Err("Could not open downloaded script".to_string())
-        }
+            Err(e) => Err(Error::io("Could not open downloaded script", e))
+        };
+        let _ = remove_file(&download_path);
+        result
     }
 
     /// This function handles the actual legwork of downloading a file from the internet.
     /// It takes the url to the file on the internet and the path to the resulting output file.
     /// To download a file you must have curl installed and in your path!
     /// In the future, this wont depend on curl.
-    pub fn download_file(url: String, output_file: String) -> Result<(), String> {
+    pub fn download_file(url: String, output_file: String) -> Result<(), Error> {
         // For now, we're using curl.
         // I'd like to make this less dependant on the OS,
         // so in the future this will be an actually HTTP request or something.
-        match Platform::command(format!("curl '{}' -o {}", url, output_file)) {
+        // Follow redirects, because most release downloads are redirected,
+        // and fail on http errors instead of saving the error page.
+        let mut child = match Command::new("curl")
+            .args(["-fsSL", &url, "-o", &output_file])
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => return Err(Error::io(format!("Could not run curl to download the file at {}, is it installed?", url), e))
+        };
+
+        // Report how much has been written to the output file until curl is done
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {
+                    if let Ok(metadata) = std::fs::metadata(&output_file) {
+                        observer().download_progress(&url, metadata.len(), None);
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                },
+                Err(e) => return Err(Error::io(format!("Curl failed to download the file at {} to {}", url, output_file), e))
+            }
+        };
+
+        if !status.success() {
+            let mut error = String::new();
+            if let Some(mut stderr) = child.stderr.take() {
+                let _ = stderr.read_to_string(&mut error);
+            }
+            return match error.trim() {
+                "" => Err(Error::download(url, format!("curl exited with {}", status))),
+                error => Err(Error::download(&url, error)),
+            };
+        }
+
+        let size = std::fs::metadata(&output_file).map(|m| m.len()).unwrap_or(0);
+        observer().download_progress(&url, size, Some(size));
+        Ok(())
+    }
+
+    /// This function downloads an archive and extracts it into `destination`.
+    /// If `sha256` is given, the download is checked against it before anything is
+    /// extracted. The archive is removed after it has been extracted.
+    pub fn download_archive(url: String, destination: PathBuf, options: &ArchiveOptions) -> Result<(), Error> {
+        let format = match ArchiveFormat::from_url(url.clone()) {
+            Some(f) => f,
+            None => return Err(Error::parse(format!("Could not tell the archive format of '{}', expected .tar.gz, .tar.xz, .tar.zst, .tar, or .zip", url)))
+        };
+
+        // Download the archive next to the other temporary files. Each download gets
+        // its own number, because archives downloaded at the same time often have the
+        // same name, like `v1.0.tar.gz`
+        let without_query = url.split(['?', '#']).next().unwrap_or("");
+        let file_name = without_query.rsplit('/').next().unwrap_or("archive");
+        let mut archive_path = std::env::temp_dir();
+        archive_path.push(format!(
+            "rapture-{}-{}-{}",
+            std::process::id(),
+            DOWNLOADS.fetch_add(1, Ordering::SeqCst),
+            file_name
+        ));
+        Self::download_file(url.clone(), path_to_string(archive_path.clone()))?;
+
+        let result = Self::verify_sha256(&archive_path, &options.sha256)
+            .and_then(|_| Self::extract_archive(&archive_path, format, &destination, options.strip));
+        let _ = remove_file(&archive_path);
+        result
+    }
+
+    /// This function downloads the prebuilt binary for the current platform.
+    /// See BinaryOptions for how the url is chosen.
+    /// 
+    /// If the url is an archive, it is extracted into `destination` like `fetch-archive`,
+    /// and the files given with `bin=` are marked as executable. Otherwise the download
+    /// is the binary itself. It is saved in `destination` and marked as executable.
+    pub fn download_binary(template: String, destination: PathBuf, options: &BinaryOptions) -> Result<(), Error> {
+        let (url, sha256) = options.resolve(&template, Platform::get().os_name(), Platform::arch());
+
+        if ArchiveFormat::from_url(url.clone()).is_some() {
+            let mut archive = options.archive.clone();
+            archive.sha256 = sha256;
+            Self::download_archive(url, destination.clone(), &archive)?;
+        } else {
+            if let Err(e) = create_dir_all(&destination) {
+                return Err(Error::io(format!("Failed to create directory {}", destination.display()), e));
+            }
+
+            let without_query = url.split(['?', '#']).next().unwrap_or("");
+            let name = match &options.name {
+                Some(name) => name.clone(),
+                None => without_query.rsplit('/').next().unwrap_or("").to_string()
+            };
+            let binary = PathManager::safe_join(&destination, &name)?;
+
+            Self::download_file(url.clone(), path_to_string(binary.clone()))?;
+            if let Err(e) = Self::verify_sha256(&binary, &sha256) {
+                let _ = remove_file(&binary);
+                return Err(e);
+            }
+            set_mode(binary, "755")?;
+        }
+
+        for bin in &options.bins {
+            set_mode(PathManager::safe_join(&destination, bin)?, "755")?;
+        }
+        Ok(())
+    }
+
+    /// This function checks a downloaded file against the sha256 hash given by a script.
+    /// If no hash was given, the file is not checked.
+    pub fn verify_sha256(path: &Path, expected: &Option<String>) -> Result<(), Error> {
+        let expected = match expected {
+            Some(e) => e,
+            None => return Ok(())
+        };
+
+        let mut file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(format!("Could not open downloaded file '{}'", path.display()), e))
+        };
+        let mut hasher = Sha256::new();
+        if let Err(e) = io::copy(&mut file, &mut hasher) {
+            return Err(Error::io(format!("Could not read downloaded file '{}'", path.display()), e));
+        }
+
+        let actual = format!("{:x}", hasher.finalize());
+        if &actual == expected {
+            Ok(())
+        } else {
+            Err(Error::download(path.display(), format!("The sha256 hash of the download is {}, but {} was expected", actual, expected)))
+        }
+    }
+
+    /// This function extracts an archive into `destination`, removing the first `strip`
+    /// components of every path in it. Files keep their permissions, and every path
+    /// must stay inside `destination`.
+    pub fn extract_archive(archive: &Path, format: ArchiveFormat, destination: &Path, strip: usize) -> Result<(), Error> {
+        if let Err(e) = create_dir_all(destination) {
+            return Err(Error::io(format!("Failed to create directory {}", destination.display()), e));
+        }
+
+        let file = match File::open(archive) {
+            Ok(f) => BufReader::new(f),
+            Err(e) => return Err(Error::io(format!("Could not open archive '{}'", archive.display()), e))
+        };
+
+        match format {
+            ArchiveFormat::Tar => Self::extract_tar(file, destination, strip),
+            ArchiveFormat::TarGz => Self::extract_tar(GzDecoder::new(file), destination, strip),
+            ArchiveFormat::TarXz => Self::extract_tar(XzDecoder::new(file), destination, strip),
+            ArchiveFormat::TarZst => match zstd::stream::read::Decoder::with_buffer(file) {
+                Ok(decoder) => Self::extract_tar(decoder, destination, strip),
+                Err(e) => Err(Error::io(format!("Could not decompress archive '{}'", archive.display()), e))
+            },
+            ArchiveFormat::Zip => Self::extract_zip(archive, destination, strip),
+        }
+    }
+
+    /// Extract a tar archive from a stream that has already been decompressed.
+    fn extract_tar<R: Read>(reader: R, destination: &Path, strip: usize) -> Result<(), Error> {
+        let mut archive = Archive::new(reader);
+        let entries = match archive.entries() {
+            Ok(e) => e,
+            Err(e) => return Err(Error::io("Could not read archive", e))
+        };
+
+        for entry in entries {
+            let mut entry = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(Error::io("Could not read archive", e))
+            };
+            let path = match entry.path() {
+                Ok(p) => p.to_path_buf(),
+                Err(e) => return Err(Error::io("Invalid path in archive", e))
+            };
+            let target = match strip_components(&path, strip) {
+                Some(relative) => PathManager::safe_join(destination, &relative)?,
+                None => continue
+            };
+
+            let result = match entry.header().entry_type() {
+                EntryType::Directory => create_dir_all(&target),
+                // Hard links point to another file in the archive, which must also be inside the destination
+                EntryType::Link => {
+                    let source = match entry.link_name() {
+                        Ok(Some(source)) => match strip_components(&source, strip) {
+                            Some(relative) => PathManager::safe_join(destination, &relative)?,
+                            None => return Err(Error::parse(format!("Invalid hard link '{}' in archive", path.display())))
+                        },
+                        _ => return Err(Error::parse(format!("Invalid hard link '{}' in archive", path.display())))
+                    };
+                    make_parent(&target)?;
+                    if symlink_metadata(&target).is_ok() {
+                        let _ = remove_file(&target);
+                    }
+                    hard_link(source, &target)
+                },
+                _ => {
+                    make_parent(&target)?;
+                    entry.unpack(&target).map(|_| ())
+                }
+            };
+
+            if let Err(e) = result {
+                return Err(Error::io(format!("Could not extract '{}'", path.display()), e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Extract a zip archive.
+    fn extract_zip(archive: &Path, destination: &Path, strip: usize) -> Result<(), Error> {
+        let file = match File::open(archive) {
+            Ok(f) => f,
+            Err(e) => return Err(Error::io(format!("Could not open archive '{}'", archive.display()), e))
+        };
+        let mut zip = match ZipArchive::new(file) {
+            Ok(z) => z,
+            Err(e) => return Err(Error::io(format!("Could not read archive '{}'", archive.display()), e.into()))
+        };
+
+        for i in 0..zip.len() {
+            let mut file = match zip.by_index(i) {
+                Ok(f) => f,
+                Err(e) => return Err(Error::io(format!("Could not read archive '{}'", archive.display()), e.into()))
+            };
+            let path = PathBuf::from(file.name());
+            let target = match strip_components(&path, strip) {
+                Some(relative) => PathManager::safe_join(destination, &relative)?,
+                None => continue
+            };
+
+            if file.is_dir() {
+                if let Err(e) = create_dir_all(&target) {
+                    return Err(Error::io(format!("Failed to create directory {}", target.display()), e));
+                }
+                continue;
+            }
+
+            make_parent(&target)?;
+            let mut output = match File::create(&target) {
+                Ok(f) => f,
+                Err(e) => return Err(Error::io(format!("Could not open file '{}'", target.display()), e))
+            };
+            if let Err(e) = io::copy(&mut file, &mut output) {
+                return Err(Error::io(format!("Could not extract '{}'", path.display()), e));
+            }
+            if let Some(mode) = file.unix_mode() {
+                set_mode(target, &format!("{:o}", mode & 0o7777))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Remove the first `strip` components of a path from an archive.
+/// Returns None if nothing is left of the path, in which case the entry is skipped.
+fn strip_components(path: &Path, strip: usize) -> Option<String> {
+    let components: Vec<String> = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .skip(strip)
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+
+    if components.is_empty() {
+        None
+    } else {
+        Some(components.join("/"))
+    }
+}
+
+/// Create the directory a file is extracted into, if the archive didn't.
+fn make_parent(path: &Path) -> Result<(), Error> {
+    match path.parent() {
+        Some(parent) => match create_dir_all(parent) {
             Ok(_) => Ok(()),
-            Err(_) => Err(format!("Curl failed to download the file at {} to {}", url, output_file))
+            Err(e) => Err(Error::io(format!("Failed to create directory {}", parent.display()), e))
+        },
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+    use tar::{Builder, Header};
+    use tempfile::tempdir;
+    use zip::write::{FileOptions, ZipWriter};
+
+    /// A tar header for `path`, written as it is, so the tests can build
+    /// archives with paths the tar crate wouldn't let through.
+    fn header(path: &str, size: usize, mode: u32, kind: EntryType) -> Header {
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_size(size as u64);
+        header.set_mode(mode);
+        header.set_entry_type(kind);
+        header.set_cksum();
+        header
+    }
+
+    /// Build a tar archive at `path` from (path, contents, mode) entries.
+    /// Paths ending in `/` are directories.
+    fn tar(path: &Path, entries: &[(&str, &str, u32)]) {
+        let mut builder = Builder::new(File::create(path).unwrap());
+        for (name, contents, mode) in entries {
+            let kind = if name.ends_with('/') { EntryType::Directory } else { EntryType::Regular };
+            builder.append(&header(name, contents.len(), *mode, kind), contents.as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Build a zip archive at `path` from (path, contents, mode) entries.
+    fn zip(path: &Path, entries: &[(&str, &str, u32)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, contents, mode) in entries {
+            let options = FileOptions::default().unix_permissions(*mode);
+            if name.ends_with('/') {
+                zip.add_directory(*name, options).unwrap();
+            } else {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
         }
+        zip.finish().unwrap();
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn archive_formats_are_guessed_from_the_url() {
+        let formats = [
+            ("https://x.org/a.tar.gz?raw=true", Some(ArchiveFormat::TarGz)),
+            ("A.TGZ", Some(ArchiveFormat::TarGz)),
+            ("a.tar.xz", Some(ArchiveFormat::TarXz)),
+            ("a.tar.zst#top", Some(ArchiveFormat::TarZst)),
+            ("a.tar", Some(ArchiveFormat::Tar)),
+            ("a.zip", Some(ArchiveFormat::Zip)),
+            ("a.gz", None),
+            ("https://x.org/tool", None),
+        ];
+        for (url, format) in formats {
+            assert!(ArchiveFormat::from_url(url) == format, "{}", url);
+        }
+    }
+
+    #[test]
+    fn strip_removes_leading_components() {
+        assert_eq!(strip_components(Path::new("tool-1.0/bin/tool"), 0), Some("tool-1.0/bin/tool".to_string()));
+        assert_eq!(strip_components(Path::new("./tool-1.0/bin/tool"), 1), Some("bin/tool".to_string()));
+        assert_eq!(strip_components(Path::new("tool-1.0/bin/tool"), 2), Some("tool".to_string()));
+        assert_eq!(strip_components(Path::new("tool-1.0/"), 1), None);
+        assert_eq!(strip_components(Path::new("tool-1.0/bin/tool"), 3), None);
+    }
+
+    #[test]
+    fn tar_archives_are_extracted_with_strip() {
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("tool.tar");
+        tar(&archive, &[("tool-1.0/", "", 0o755), ("tool-1.0/bin/tool", "#!/bin/sh\n", 0o755), ("tool-1.0/README", "hi", 0o644)]);
+
+        let destination = dir.path().join("out");
+        Downloader::extract_archive(&archive, ArchiveFormat::Tar, &destination, 1).unwrap();
+        assert_eq!(read_to_string(destination.join("bin/tool")).unwrap(), "#!/bin/sh\n");
+        assert_eq!(read_to_string(destination.join("README")).unwrap(), "hi");
+        assert!(!destination.join("tool-1.0").exists());
+        #[cfg(unix)]
+        {
+            assert_eq!(mode(&destination.join("bin/tool")), 0o755);
+            assert_eq!(mode(&destination.join("README")), 0o644);
+        }
+    }
+
+    #[test]
+    fn tar_entries_outside_the_destination_are_rejected() {
+        for path in ["../evil", "a/../../evil", "/tmp/evil"] {
+            let dir = tempdir().unwrap();
+            let archive = dir.path().join("evil.tar");
+            tar(&archive, &[(path, "evil", 0o644)]);
+            let destination = dir.path().join("out");
+            let result = Downloader::extract_archive(&archive, ArchiveFormat::Tar, &destination, 0);
+            assert!(matches!(result, Err(Error::Permission(_))), "extracted '{}'", path);
+            assert!(!dir.path().join("evil").exists());
+        }
+    }
+
+    #[test]
+    fn zip_archives_are_extracted_with_their_modes() {
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("tool.zip");
+        zip(&archive, &[("tool/", "", 0o755), ("tool/tool", "binary", 0o755), ("tool/notes.txt", "notes", 0o600)]);
+
+        let destination = dir.path().join("out");
+        Downloader::extract_archive(&archive, ArchiveFormat::Zip, &destination, 1).unwrap();
+        assert_eq!(read_to_string(destination.join("tool")).unwrap(), "binary");
+        assert_eq!(read_to_string(destination.join("notes.txt")).unwrap(), "notes");
+        #[cfg(unix)]
+        {
+            assert_eq!(mode(&destination.join("tool")), 0o755);
+            assert_eq!(mode(&destination.join("notes.txt")), 0o600);
+        }
+    }
+
+    #[test]
+    fn zip_entries_outside_the_destination_are_rejected() {
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("evil.zip");
+        zip(&archive, &[("../evil", "evil", 0o644)]);
+        let result = Downloader::extract_archive(&archive, ArchiveFormat::Zip, &dir.path().join("out"), 0);
+        assert!(matches!(result, Err(Error::Permission(_))));
+        assert!(!dir.path().join("evil").exists());
+    }
+
+    #[test]
+    fn archives_that_dont_match_their_hash_are_not_extracted() {
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("tool.tar");
+        tar(&archive, &[("tool", "binary", 0o755)]);
+        let url = format!("file://{}", archive.display());
+        let destination = dir.path().join("out");
+
+        let wrong = ArchiveOptions { sha256: Some("0".repeat(64)), ..ArchiveOptions::default() };
+        match Downloader::download_archive(url.clone(), destination.clone(), &wrong) {
+            Err(Error::Download { message, .. }) => assert!(message.contains("sha256"), "{}", message),
+            other => panic!("{:?}", other.err()),
+        }
+        assert!(!destination.exists());
+
+        let hash = format!("{:x}", Sha256::digest(std::fs::read(&archive).unwrap()));
+        let right = ArchiveOptions { sha256: Some(hash), ..ArchiveOptions::default() };
+        Downloader::download_archive(url, destination.clone(), &right).unwrap();
+        assert_eq!(read_to_string(destination.join("tool")).unwrap(), "binary");
+    }
+
+    #[test]
+    fn binary_options_are_parsed() {
+        let (template, options) = BinaryOptions::parse(
+            "https://x.org/tool-{os}.{ext} into tools as tool sha256=ABC strip=1 bin=a bin=b os.macos=darwin sha256.linux-x86_64=def"
+        ).unwrap();
+        assert_eq!(template, "https://x.org/tool-{os}.{ext}");
+        assert_eq!(options.archive.into.as_deref(), Some("tools"));
+        assert_eq!(options.archive.sha256.as_deref(), Some("abc"));
+        assert_eq!(options.archive.strip, 1);
+        assert_eq!(options.name.as_deref(), Some("tool"));
+        assert_eq!(options.bins, vec!["a", "b"]);
+        assert_eq!(options.overrides, vec![
+            ("os".to_string(), "macos".to_string(), "darwin".to_string()),
+            ("sha256".to_string(), "linux-x86_64".to_string(), "def".to_string()),
+        ]);
+
+        for args in ["", "url as", "url into", "url strip=x", "url color=red", "url size.linux=1", "url nothing"] {
+            assert!(BinaryOptions::parse(args).is_err(), "parsed '{}'", args);
+        }
+    }
+
+    #[test]
+    fn overrides_for_a_whole_target_win() {
+        let (_, options) = BinaryOptions::parse("url arch.x86_64=amd64 arch.linux=l64 arch.linux-x86_64=both").unwrap();
+        assert_eq!(options.lookup("arch", "linux", "x86_64").as_deref(), Some("both"));
+        assert_eq!(options.lookup("arch", "linux", "aarch64").as_deref(), Some("l64"));
+        assert_eq!(options.lookup("arch", "macos", "x86_64").as_deref(), Some("amd64"));
+        assert_eq!(options.lookup("arch", "macos", "aarch64"), None);
+        assert_eq!(options.lookup("os", "linux", "x86_64"), None);
+    }
+
+    #[test]
+    fn binaries_are_resolved_for_each_platform() {
+        let (template, options) = BinaryOptions::parse(
+            "https://x.org/tool-{os}-{arch}.{ext} sha256=AAA os.macos=darwin arch.x86_64=amd64 url.windows=https://x.org/tool.exe sha256.windows=bbb"
+        ).unwrap();
+        assert_eq!(options.resolve(&template, "linux", "x86_64"), ("https://x.org/tool-linux-amd64.tar.gz".to_string(), Some("aaa".to_string())));
+        assert_eq!(options.resolve(&template, "macos", "aarch64"), ("https://x.org/tool-darwin-aarch64.tar.gz".to_string(), Some("aaa".to_string())));
+        assert_eq!(options.resolve(&template, "windows", "x86_64"), ("https://x.org/tool.exe".to_string(), Some("bbb".to_string())));
+
+        // A platform without overrides gets the defaults, and no hash if none was given
+        let (template, options) = BinaryOptions::parse("https://x.org/tool-{os}-{arch}.{ext} os.linux=gnu").unwrap();
+        assert_eq!(options.resolve(&template, "freebsd", "riscv64"), ("https://x.org/tool-freebsd-riscv64.tar.gz".to_string(), None));
+        assert_eq!(options.resolve(&template, "windows", "x86_64").0, "https://x.org/tool-windows-x86_64.zip");
+    }
+
+    #[test]
+    fn binaries_that_dont_match_their_hash_are_removed() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("tool");
+        write(&source, "binary").unwrap();
+        let destination = dir.path().join("out");
+
+        let (template, options) = BinaryOptions::parse(format!("file://{} sha256={}", source.display(), "0".repeat(64))).unwrap();
+        assert!(matches!(Downloader::download_binary(template, destination.clone(), &options), Err(Error::Download { .. })));
+        assert!(!destination.join("tool").exists());
+
+        let hash = format!("{:x}", Sha256::digest(b"binary"));
+        let (template, options) = BinaryOptions::parse(format!("file://{} as renamed sha256={}", source.display(), hash)).unwrap();
+        Downloader::download_binary(template, destination.clone(), &options).unwrap();
+        assert_eq!(read_to_string(destination.join("renamed")).unwrap(), "binary");
+        #[cfg(unix)]
+        assert_eq!(mode(&destination.join("renamed")), 0o755);
     }
-}
\ No newline at end of file
+}
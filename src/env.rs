@@ -0,0 +1,552 @@
+/// This part of the crate manages the environment that rapture exposes to the
+/// user's shell. Instead of appending an `export PATH=...` line to `.bashrc`
+/// for every package, rapture keeps a small state file in the install directory
+/// and regenerates `env.sh`, `env.fish`, and `env.ps1` from it. The user's profile
+/// only has to source one of those files once, which `source_from_profile` sets up
+/// for bash, zsh, and fish.
+use crate::path::{PathManager, path_to_string};
+use crate::platform::Platform;
+use crate::registry::{user_environment, set_user_var};
+use crate::error::Error;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+/// The name of the file in the install directory that records every path
+/// and environment variable rapture has been asked to manage.
+const STATE_FILE_NAME: &str = "env.state";
+
+/// The name of the file in the install directory that is locked while the
+/// environment is updated, so two rapt processes don't update it at the same time.
+const LOCK_FILE_NAME: &str = "env.lock";
+
+/// Held while the environment is updated. Dependencies installed at the same time
+/// all add paths and variables, and each update reads the state file, changes it,
+/// and writes it back, so updates running together would lose each other's changes.
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// The environment stays locked until this is dropped, see EnvManager::lock.
+pub struct EnvLock {
+    _guard: MutexGuard<'static, ()>,
+    _file: File,
+}
+
+/// The shells rapture knows how to generate an environment file for.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Shell {
+    Sh,
+    Fish,
+    PowerShell,
+}
+
+impl Shell {
+    /// Every shell we generate a file for when the environment changes.
+    pub fn all() -> Vec<Self> {
+        vec![Shell::Sh, Shell::Fish, Shell::PowerShell]
+    }
+
+    /// Get a shell from the name given on the commandline.
+    pub fn from_name<S: ToString>(name: S) -> Result<Self, Error> {
+        match name.to_string().to_lowercase().as_str() {
+            "sh" | "bash" | "zsh" => Ok(Shell::Sh),
+            "fish" => Ok(Shell::Fish),
+            "powershell" | "pwsh" | "ps1" => Ok(Shell::PowerShell),
+            other => Err(Error::parse(format!("Unknown shell '{}', expected sh, fish, or powershell", other))),
+        }
+    }
+
+    /// Guess the user's shell. Windows users get PowerShell, and on every
+    /// other platform we look at the `SHELL` environment variable.
+    pub fn detect() -> Self {
+        if Platform::get() == Platform::Windows {
+            return Shell::PowerShell;
+        }
+
+        match std::env::var("SHELL") {
+            Ok(shell) if shell.ends_with("fish") => Shell::Fish,
+            _ => Shell::Sh,
+        }
+    }
+
+    /// The name of the generated environment file for this shell.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Shell::Sh => "env.sh",
+            Shell::Fish => "env.fish",
+            Shell::PowerShell => "env.ps1",
+        }
+    }
+
+    /// Render the managed paths and variables as a script for this shell.
+    fn render(self, entries: &[EnvEntry]) -> String {
+        let paths: Vec<String> = entries
+            .iter()
+            .filter_map(|e| match e {
+                EnvEntry::Path(path) => Some(path.clone()),
+                EnvEntry::Var(_, _) => None,
+            })
+            .collect();
+
+        let mut result = String::from("# This file is generated by rapture. Do not edit it by hand.\n");
+        match self {
+            Shell::Sh => {
+                if !paths.is_empty() {
+                    let joined: Vec<String> = paths.iter().map(|p| sh_escape(p)).collect();
+                    result += &format!("export PATH=\"{}:$PATH\"\n", joined.join(":"));
+                }
+                for entry in entries {
+                    if let EnvEntry::Var(name, value) = entry {
+                        result += &format!("export {}=\"{}\"\n", name, sh_escape(value));
+                    }
+                }
+            },
+            Shell::Fish => {
+                if !paths.is_empty() {
+                    let joined: Vec<String> = paths.iter().map(|p| fish_quote(p)).collect();
+                    result += &format!("set -gx PATH {} $PATH\n", joined.join(" "));
+                }
+                for entry in entries {
+                    if let EnvEntry::Var(name, value) = entry {
+                        result += &format!("set -gx {} {}\n", name, fish_quote(value));
+                    }
+                }
+            },
+            Shell::PowerShell => {
+                if !paths.is_empty() {
+                    let joined: Vec<String> = paths.iter().map(|p| format!("{};", p)).collect();
+                    result += &format!("$env:PATH = {} + $env:PATH\n", powershell_quote(&joined.concat()));
+                }
+                for entry in entries {
+                    if let EnvEntry::Var(name, value) = entry {
+                        result += &format!("$env:{} = {}\n", name, powershell_quote(value));
+                    }
+                }
+            },
+        }
+        result
+    }
+}
+
+/// Escape a string so it can be placed inside double quotes in sh.
+fn sh_escape(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        if let '\\' | '"' | '$' | '`' = c {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Check that `name` can be used as an environment variable in every shell:
+/// a letter or underscore, followed by letters, digits, and underscores.
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+/// Quote a string with single quotes for fish.
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Quote a string with single quotes for PowerShell.
+fn powershell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// A single path or variable managed by rapture.
+#[derive(Clone, PartialEq)]
+pub enum EnvEntry {
+    /// A directory prepended to the user's PATH
+    Path(String),
+    /// An environment variable with its value
+    Var(String, String),
+}
+
+/// An empty struct with an impl used for managing the generated environment files.
+pub struct EnvManager {}
+
+impl EnvManager {
+    /// The absolute path to the state file the environment files are generated from.
+    pub fn state_file() -> String {
+        let mut state_file = PathBuf::new();
+        state_file.push(PathManager::install_dir());
+        state_file.push(STATE_FILE_NAME);
+        path_to_string(state_file)
+    }
+
+    /// The absolute path to the generated environment file for a shell.
+    pub fn env_file(shell: Shell) -> String {
+        let mut env_file = PathBuf::new();
+        env_file.push(PathManager::install_dir());
+        env_file.push(shell.file_name());
+        path_to_string(env_file)
+    }
+
+    /// This function locks the environment until the returned lock is dropped, for
+    /// this process with a mutex, and for every other rapt process with a lock on
+    /// `~/.rapture/env.lock`. Every change to the state file, the generated files,
+    /// the user's profile, or the registry happens while it is held.
+    pub fn lock() -> Result<EnvLock, Error> {
+        let guard = match ENV_LOCK.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        PathManager::make_install_dir()?;
+        let mut path = PathBuf::new();
+        path.push(PathManager::install_dir());
+        path.push(LOCK_FILE_NAME);
+        let file = match OpenOptions::new().create(true).truncate(false).write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::io(format!("Could not open the lock file '{}'", path.display()), e)),
+        };
+        match file.lock() {
+            Ok(()) => Ok(EnvLock { _guard: guard, _file: file }),
+            Err(e) => Err(Error::io(format!("Could not lock '{}'", path.display()), e)),
+        }
+    }
+
+    /// Read every managed path and variable from the state file.
+    /// If the state file doesn't exist yet, nothing is managed.
+    pub fn load() -> Result<Vec<EnvEntry>, Error> {
+        let mut contents = String::new();
+        if let Ok(mut f) = File::open(Self::state_file()) {
+            if let Err(e) = f.read_to_string(&mut contents) {
+                return Err(Error::io(format!("Could not read environment state file '{}'", Self::state_file()), e));
+            }
+        }
+
+        let mut entries = vec![];
+        for line in contents.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("path"), Some(path)) => entries.push(EnvEntry::Path(path.to_string())),
+                (Some("env"), Some(assignment)) => {
+                    let mut assignment = assignment.splitn(2, ' ');
+                    if let (Some(name), value) = (assignment.next(), assignment.next()) {
+                        // Older versions of rapture let names like `1BAD` through, which
+                        // would break every generated file, so they are dropped here
+                        if !is_var_name(name) {
+                            continue;
+                        }
+                        entries.push(EnvEntry::Var(name.to_string(), value.unwrap_or("").to_string()));
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Overwrite the state file with the given entries.
+    fn save(entries: &[EnvEntry]) -> Result<(), Error> {
+        PathManager::make_install_dir()?;
+
+        let mut contents = String::new();
+        for entry in entries {
+            match entry {
+                EnvEntry::Path(path) => contents += &format!("path {}\n", path),
+                EnvEntry::Var(name, value) => contents += &format!("env {} {}\n", name, value),
+            }
+        }
+        write_file(Self::state_file(), contents)
+    }
+
+    /// Add a directory to the managed PATH and regenerate the environment files.
+    /// Adding a directory that is already managed does nothing.
+    pub fn add_path<S: ToString>(path: S) -> Result<(), Error> {
+        let _lock = Self::lock()?;
+        Self::insert_path(path.to_string())
+    }
+
+    /// Add a directory to the managed PATH, while the environment is locked.
+    fn insert_path(path: String) -> Result<(), Error> {
+        let mut entries = Self::load()?;
+        let entry = EnvEntry::Path(path);
+        if !entries.contains(&entry) {
+            entries.push(entry);
+            Self::save(&entries)?;
+        }
+        Self::write_env_files()
+    }
+
+    /// Remove a directory from the managed PATH and regenerate the environment files.
+    pub fn remove_path<S: ToString>(path: S) -> Result<(), Error> {
+        let _lock = Self::lock()?;
+        let entry = EnvEntry::Path(path.to_string());
+        let entries: Vec<EnvEntry> = Self::load()?.into_iter().filter(|e| e != &entry).collect();
+        Self::save(&entries)?;
+        Self::write_env_files()
+    }
+
+    /// Set a managed environment variable and regenerate the environment files.
+    /// Setting a variable that is already managed replaces its value.
+    /// On windows, the variable is also set in the registry.
+    pub fn set_var<S: ToString>(name: S, value: S) -> Result<(), Error> {
+        let name = name.to_string();
+        if !is_var_name(&name) {
+            return Err(Error::parse(format!("Invalid environment variable name '{}'", name)));
+        }
+
+        let _lock = Self::lock()?;
+        let mut entries: Vec<EnvEntry> = Self::load()?
+            .into_iter()
+            .filter(|e| match e {
+                EnvEntry::Var(n, _) => n != &name,
+                EnvEntry::Path(_) => true,
+            })
+            .collect();
+        entries.push(EnvEntry::Var(name.clone(), value.to_string()));
+        Self::save(&entries)?;
+        Self::write_env_files()?;
+
+        if Platform::get() == Platform::Windows {
+            set_user_var(user_environment()?.as_mut(), &name, &value.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Render the managed environment for a shell.
+    /// This is what `rapt env` prints for the user to `eval`.
+    pub fn render(shell: Shell) -> Result<String, Error> {
+        Ok(shell.render(&Self::load()?))
+    }
+
+    /// Rewrite the environment file for every shell from the state file.
+    pub fn regenerate() -> Result<(), Error> {
+        let _lock = Self::lock()?;
+        Self::write_env_files()
+    }
+
+    /// Rewrite the environment files, while the environment is locked.
+    fn write_env_files() -> Result<(), Error> {
+        let entries = Self::load()?;
+        for shell in Shell::all() {
+            write_file(Self::env_file(shell), shell.render(&entries))?;
+        }
+        Ok(())
+    }
+
+    /// Make sure the user's profiles source the generated environment files.
+    /// `.bashrc` always sources `env.sh`, and so does `.zshrc` if the user has one.
+    /// If fish is set up, `~/.config/fish/conf.d/rapture.fish` sources `env.fish`.
+    /// This only has to happen once, after that every change to the environment
+    /// is picked up by regenerating the environment files.
+    ///
+    /// Older versions of rapture appended an `export PATH=...` line to `.bashrc`
+    /// for each package. Those lines are moved into the state file here.
+    pub fn source_from_profile() -> Result<(), Error> {
+        // Windows doesn't have a profile we can source from.
+        if Platform::get() == Platform::Windows {
+            return Ok(());
+        }
+
+        let _lock = Self::lock()?;
+        let home = PathBuf::from(PathManager::home_dir());
+        let source_line = format!(". \"{}\"", Self::env_file(Shell::Sh));
+
+        Self::source_from(home.join(".bashrc"), &source_line)?;
+
+        let zshrc = home.join(".zshrc");
+        if zshrc.is_file() {
+            Self::source_from(zshrc, &source_line)?;
+        }
+
+        // fish runs every file in conf.d when it starts
+        let fish_config = home.join(".config").join("fish");
+        if fish_config.is_dir() {
+            let conf_d = fish_config.join("conf.d");
+            if let Err(e) = create_dir_all(&conf_d) {
+                return Err(Error::io(format!("Could not create directory '{}'", path_to_string(conf_d)), e));
+            }
+            let contents = format!("source \"{}\"\n", Self::env_file(Shell::Fish));
+            write_file(path_to_string(conf_d.join("rapture.fish")), contents)?;
+        }
+        Ok(())
+    }
+
+    /// Add `source_line` to the end of a shell profile, unless it's already there,
+    /// and move the profile's legacy `export PATH=...` lines into the state file.
+    fn source_from(profile: PathBuf, source_line: &str) -> Result<(), Error> {
+        let mut contents = String::new();
+        if let Ok(mut f) = File::open(profile.clone()) {
+            if let Err(e) = f.read_to_string(&mut contents) {
+                return Err(Error::io(format!("Could not read {}", path_to_string(profile)), e));
+            }
+        }
+
+        let legacy_prefix = format!("export PATH=\"$PATH:{}", PathManager::install_dir());
+
+        let mut legacy_paths = vec![];
+        let mut lines = vec![];
+        for line in contents.lines() {
+            if line.starts_with(&legacy_prefix) {
+                legacy_paths.push(line.trim_start_matches("export PATH=\"$PATH:").trim_end_matches('"').to_string());
+            } else {
+                lines.push(line.to_string());
+            }
+        }
+
+        if legacy_paths.is_empty() && lines.iter().any(|line| line == source_line) {
+            return Ok(());
+        }
+
+        for path in legacy_paths {
+            Self::insert_path(path)?;
+        }
+        if !lines.iter().any(|line| line == source_line) {
+            lines.push(source_line.to_string());
+        }
+
+        let mut new_contents = lines.join("\n");
+        new_contents.push('\n');
+        write_file(path_to_string(profile), new_contents)
+    }
+}
+
+/// Replace the contents of a file, creating it if it doesn't exist.
+fn write_file(path: String, contents: String) -> Result<(), Error> {
+    let mut file = match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path.clone())
+    {
+        Ok(f) => Ok(f),
+        Err(e) => Err(Error::io(format!("Could not open file '{}'", path), e)),
+    }?;
+
+    match file.write_all(contents.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io(format!("Failed to write to file {}", path), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::temp_home;
+    use std::fs::{create_dir_all, read_to_string};
+
+    fn entries() -> Vec<EnvEntry> {
+        vec![
+            EnvEntry::Path("/home/me/.rapture/bin".to_string()),
+            EnvEntry::Path("/opt/it's here".to_string()),
+            EnvEntry::Var("GREETING".to_string(), "say \"hi\" to $USER's `pc` \\o/".to_string()),
+        ]
+    }
+
+    #[test]
+    fn variable_names_must_start_with_a_letter_or_underscore() {
+        for name in ["PATH", "_private", "GO111MODULE", "a"] {
+            assert!(is_var_name(name), "rejected '{}'", name);
+        }
+        for name in ["", "1BAD", "A-B", "A B", "Ü", "$X"] {
+            assert!(!is_var_name(name), "accepted '{}'", name);
+            assert!(EnvManager::set_var(name, "x").is_err(), "set '{}'", name);
+        }
+    }
+
+    #[test]
+    fn state_is_saved_and_loaded() {
+        let (_guard, _home) = temp_home();
+        assert!(EnvManager::load().unwrap().is_empty());
+        EnvManager::save(&entries()).unwrap();
+        assert!(EnvManager::load().unwrap() == entries());
+
+        EnvManager::set_var("GREETING", "bye").unwrap();
+        EnvManager::add_path("/opt/it's here").unwrap();
+        EnvManager::remove_path("/home/me/.rapture/bin").unwrap();
+        let expected = vec![
+            EnvEntry::Path("/opt/it's here".to_string()),
+            EnvEntry::Var("GREETING".to_string(), "bye".to_string()),
+        ];
+        assert!(EnvManager::load().unwrap() == expected);
+        assert_eq!(read_to_string(EnvManager::env_file(Shell::Sh)).unwrap(), Shell::Sh.render(&expected));
+    }
+
+    #[test]
+    fn invalid_names_in_the_state_file_are_dropped() {
+        let (_guard, _home) = temp_home();
+        PathManager::make_install_dir().unwrap();
+        write_file(EnvManager::state_file(), "env 1BAD x\nenv GOOD y z\npath /bin\n".to_string()).unwrap();
+        let expected = vec![EnvEntry::Var("GOOD".to_string(), "y z".to_string()), EnvEntry::Path("/bin".to_string())];
+        assert!(EnvManager::load().unwrap() == expected);
+    }
+
+    #[test]
+    fn sh_files_are_quoted() {
+        assert_eq!(Shell::Sh.render(&entries()), "# This file is generated by rapture. Do not edit it by hand.\n\
+            export PATH=\"/home/me/.rapture/bin:/opt/it's here:$PATH\"\n\
+            export GREETING=\"say \\\"hi\\\" to \\$USER's \\`pc\\` \\\\o/\"\n");
+    }
+
+    #[test]
+    fn fish_files_are_quoted() {
+        assert_eq!(Shell::Fish.render(&entries()), "# This file is generated by rapture. Do not edit it by hand.\n\
+            set -gx PATH '/home/me/.rapture/bin' '/opt/it\\'s here' $PATH\n\
+            set -gx GREETING 'say \"hi\" to $USER\\'s `pc` \\\\o/'\n");
+    }
+
+    #[test]
+    fn powershell_files_are_quoted() {
+        assert_eq!(Shell::PowerShell.render(&entries()), "# This file is generated by rapture. Do not edit it by hand.\n\
+            $env:PATH = '/home/me/.rapture/bin;/opt/it''s here;' + $env:PATH\n\
+            $env:GREETING = 'say \"hi\" to $USER''s `pc` \\o/'\n");
+    }
+
+    #[test]
+    fn empty_environments_only_have_the_comment() {
+        for shell in Shell::all() {
+            assert_eq!(shell.render(&[]), "# This file is generated by rapture. Do not edit it by hand.\n");
+        }
+    }
+
+    #[test]
+    fn legacy_path_lines_are_moved_into_the_state_file() {
+        let (_guard, home) = temp_home();
+        let install_dir = PathManager::install_dir();
+        let bashrc = home.path().join(".bashrc");
+        let legacy = format!("export PATH=\"$PATH:{}/old/bin\"", install_dir);
+        write_file(path_to_string(bashrc.clone()), format!("alias ll='ls -l'\n{}\nexport EDITOR=vi\n", legacy)).unwrap();
+
+        EnvManager::source_from_profile().unwrap();
+        let source_line = format!(". \"{}\"", EnvManager::env_file(Shell::Sh));
+        assert_eq!(read_to_string(&bashrc).unwrap(), format!("alias ll='ls -l'\nexport EDITOR=vi\n{}\n", source_line));
+        assert!(EnvManager::load().unwrap() == vec![EnvEntry::Path(format!("{}/old/bin", install_dir))]);
+        assert!(read_to_string(EnvManager::env_file(Shell::Sh)).unwrap().contains("/old/bin"));
+    }
+
+    #[test]
+    fn profiles_source_the_environment_once() {
+        let (_guard, home) = temp_home();
+        let zshrc = home.path().join(".zshrc");
+        write_file(path_to_string(zshrc.clone()), "setopt autocd\n".to_string()).unwrap();
+        create_dir_all(home.path().join(".config").join("fish")).unwrap();
+
+        for _ in 0..2 {
+            EnvManager::source_from_profile().unwrap();
+        }
+        let source_line = format!(". \"{}\"\n", EnvManager::env_file(Shell::Sh));
+        assert_eq!(read_to_string(home.path().join(".bashrc")).unwrap(), source_line);
+        assert_eq!(read_to_string(zshrc).unwrap(), format!("setopt autocd\n{}", source_line));
+        let fish = home.path().join(".config").join("fish").join("conf.d").join("rapture.fish");
+        assert_eq!(read_to_string(fish).unwrap(), format!("source \"{}\"\n", EnvManager::env_file(Shell::Fish)));
+    }
+
+    #[test]
+    fn profiles_that_dont_exist_are_left_alone() {
+        let (_guard, home) = temp_home();
+        EnvManager::source_from_profile().unwrap();
+        assert!(home.path().join(".bashrc").exists());
+        assert!(!home.path().join(".zshrc").exists());
+        assert!(!home.path().join(".config").exists());
+    }
+}
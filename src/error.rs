@@ -0,0 +1,237 @@
+/// This part of the crate defines the error every fallible function in rapture returns.
+/// The variants tell apart what went wrong, so a caller can handle a network
+/// failure differently from a mistake in a script, and `rapt` can exit with
+/// a different code for each of them.
+use serde_json::{json, Value};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::Arc;
+
+/// Everything that can go wrong while installing or capturing a package.
+///
+/// Errors are cheap to clone, because the result of installing a dependency
+/// is shared by every package that depends on it.
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// The commandline arguments given to `rapt` are missing or invalid
+    Arguments(String),
+    /// A script or one of its instructions couldn't be understood
+    Parse(String),
+    /// Reading or writing a file or directory failed
+    Io {
+        message: String,
+        source: Option<Arc<io::Error>>,
+    },
+    /// A file couldn't be downloaded, or didn't match its expected hash
+    Download { url: String, message: String },
+    /// A command exited unsuccessfully. The status is None if it was killed by a signal.
+    /// `stderr` is what the command printed to stderr, if it wasn't already shown.
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+    /// A script tried to do something it isn't allowed to,
+    /// like writing outside of its package
+    Permission(String),
+    /// The package can't be installed on this operating system. `supported`
+    /// is the platforms the package declares, like `linux` and `macos`.
+    UnsupportedPlatform {
+        package: Option<String>,
+        platform: String,
+        supported: Vec<String>,
+    },
+    /// A dependency of the package couldn't be installed
+    Dependency {
+        url: String,
+        message: String,
+        source: Option<Arc<Error>>,
+    },
+    /// An instruction of a script failed. This says where the error happened,
+    /// the source says what went wrong.
+    Script {
+        package: Option<String>,
+        line: usize,
+        instruction: String,
+        source: Arc<Error>,
+    },
+}
+
+impl Error {
+    /// The commandline arguments are missing or invalid.
+    pub fn arguments<S: ToString>(message: S) -> Self {
+        Error::Arguments(message.to_string())
+    }
+
+    /// A script couldn't be understood.
+    pub fn parse<S: ToString>(message: S) -> Self {
+        Error::Parse(message.to_string())
+    }
+
+    /// Reading or writing a file failed with `source`.
+    pub fn io<S: ToString>(message: S, source: io::Error) -> Self {
+        Error::Io { message: message.to_string(), source: Some(Arc::new(source)) }
+    }
+
+    /// Reading or writing a file failed, without an underlying io error.
+    pub fn io_message<S: ToString>(message: S) -> Self {
+        Error::Io { message: message.to_string(), source: None }
+    }
+
+    /// Downloading `url` failed.
+    pub fn download<U: ToString, S: ToString>(url: U, message: S) -> Self {
+        Error::Download { url: url.to_string(), message: message.to_string() }
+    }
+
+    /// `command` exited with `status`, after printing `stderr`.
+    pub fn command_failed<S: ToString, E: ToString>(command: S, status: Option<i32>, stderr: E) -> Self {
+        Error::CommandFailed { command: command.to_string(), status, stderr: stderr.to_string() }
+    }
+
+    /// Something isn't allowed.
+    pub fn permission<S: ToString>(message: S) -> Self {
+        Error::Permission(message.to_string())
+    }
+
+    /// The package can only be installed on the `supported` platforms, not on `platform`.
+    pub fn unsupported_platform<S: ToString>(package: Option<String>, platform: S, supported: Vec<String>) -> Self {
+        Error::UnsupportedPlatform { package, platform: platform.to_string(), supported }
+    }
+
+    /// Installing the dependency at `url` failed because of `source`.
+    pub fn dependency<S: ToString>(url: S, source: Error) -> Self {
+        let url = url.to_string();
+        Error::Dependency {
+            message: format!("Failed to install the dependency at '{}'", url),
+            url,
+            source: Some(Arc::new(source)),
+        }
+    }
+
+    /// The dependencies at `url` can't be installed, without an underlying error.
+    pub fn dependency_message<U: ToString, S: ToString>(url: U, message: S) -> Self {
+        Error::Dependency { url: url.to_string(), message: message.to_string(), source: None }
+    }
+
+    /// The instruction on `line` of a script failed because of `source`.
+    pub fn script<S: ToString>(package: Option<String>, line: usize, instruction: S, source: Error) -> Self {
+        Error::Script { package, line, instruction: instruction.to_string(), source: Arc::new(source) }
+    }
+
+    /// The name of the kind of error, like `parse` or `download`.
+    /// A failed instruction has the kind of the error that caused it.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Arguments(_) => "arguments",
+            Error::Parse(_) => "parse",
+            Error::Io { .. } => "io",
+            Error::Download { .. } => "download",
+            Error::CommandFailed { .. } => "command-failed",
+            Error::Permission(_) => "permission",
+            Error::UnsupportedPlatform { .. } => "platform",
+            Error::Dependency { .. } => "dependency",
+            Error::Script { source, .. } => source.kind(),
+        }
+    }
+
+    /// The error at the end of the chain of sources, which is what actually went wrong.
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Error::Dependency { source: Some(source), .. } | Error::Script { source, .. } => source.root_cause(),
+            _ => self,
+        }
+    }
+
+    /// The code `rapt` exits with when it fails with this error:
+    /// 
+    /// | Code | Error                                                          |
+    /// |------|----------------------------------------------------------------|
+    /// | 1    | Arguments: invalid commandline arguments                       |
+    /// | 2    | Parse: a script couldn't be understood                         |
+    /// | 3    | Io: reading or writing a file failed                           |
+    /// | 4    | Download: a download failed                                    |
+    /// | 5    | CommandFailed: a command failed                                |
+    /// | 6    | Permission: something isn't allowed                            |
+    /// | 7    | Dependency: a dependency failed                                |
+    /// | 8    | UnsupportedPlatform: the package doesn't support this platform |
+    /// 
+    /// A failed instruction exits with the code of the error that caused it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Arguments(_) => 1,
+            Error::Parse(_) => 2,
+            Error::Io { .. } => 3,
+            Error::Download { .. } => 4,
+            Error::CommandFailed { .. } => 5,
+            Error::Permission(_) => 6,
+            Error::Dependency { .. } => 7,
+            Error::UnsupportedPlatform { .. } => 8,
+            Error::Script { source, .. } => source.exit_code(),
+        }
+    }
+
+    /// This function describes the error as a JSON object with its kind, exit code,
+    /// message, and the messages of everything that caused it, outermost first.
+    pub fn to_json(&self) -> Value {
+        let mut causes = vec![];
+        let mut cause = std::error::Error::source(self);
+        while let Some(error) = cause {
+            causes.push(error.to_string());
+            cause = error.source();
+        }
+        json!({
+            "kind": self.kind(),
+            "code": self.exit_code(),
+            "message": self.to_string(),
+            "causes": causes,
+        })
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Arguments(message) | Error::Parse(message) | Error::Permission(message) => write!(f, "{}", message),
+            Error::Io { message, .. } => write!(f, "{}", message),
+            Error::Download { url, message } => write!(f, "Failed to download '{}': {}", url, message),
+            Error::CommandFailed { command, status, stderr } => {
+                match status {
+                    Some(status) => write!(f, "The command '{}' failed with exit status {}", command, status)?,
+                    None => write!(f, "The command '{}' was stopped by a signal", command)?,
+                }
+                match stderr.trim() {
+                    "" => Ok(()),
+                    stderr => write!(f, ": {}", stderr),
+                }
+            },
+            Error::UnsupportedPlatform { package, platform, supported } => {
+                match package {
+                    Some(package) => write!(f, "The package '{}'", package)?,
+                    None => write!(f, "The script")?,
+                }
+                let supported = match supported.split_last() {
+                    Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+                    _ => supported.join(""),
+                };
+                write!(f, " can only be installed on {}, not {}", supported, platform)
+            },
+            Error::Dependency { message, .. } => write!(f, "{}", message),
+            Error::Script { package: Some(package), line, instruction, .. } => {
+                write!(f, "line {} of package '{}': {}", line, package, instruction)
+            },
+            Error::Script { package: None, line, instruction, .. } => {
+                write!(f, "line {} of the script: {}", line, instruction)
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source: Some(source), .. } => Some(source.as_ref()),
+            Error::Dependency { source: Some(source), .. } | Error::Script { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
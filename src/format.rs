@@ -0,0 +1,212 @@
+use crate::manifest::{split_header, HEADER_FENCE};
+use crate::script::{takes_words, usage};
+use crate::tokenizer::{is_continued, join_continued, quote, tokenize};
+
+/// The instructions that describe the package, which stay at the top of a script.
+const HEADER_INSTRUCTIONS: [&str; 4] = ["package", "version", "description", "platforms"];
+
+/// A line of a script.
+#[derive(Clone, PartialEq)]
+enum Line {
+    Blank,
+    /// The text of a comment, without the `#`
+    Comment(String),
+    /// An instruction and its arguments
+    Instruction(String, String),
+    /// A line inside a `write-gzip` block
+    Data(String),
+}
+
+impl Line {
+    fn is_instruction(&self, names: &[&str]) -> bool {
+        match self {
+            Line::Instruction(command, _) => names.contains(&command.as_str()),
+            _ => false,
+        }
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Line::Blank => String::new(),
+            Line::Comment(text) if text.is_empty() => "#".to_string(),
+            Line::Comment(text) => format!("# {}", text),
+            Line::Instruction(command, args) if args.is_empty() => command.clone(),
+            Line::Instruction(command, args) => format!("{} {}", command, args),
+            Line::Data(data) => data.clone(),
+        }
+    }
+}
+
+/// Write the arguments of an instruction in their canonical form. The arguments of
+/// instructions that take words are split and quoted again, shell commands, text,
+/// and unknown instructions are kept as they are.
+fn normalize(command: &str, args: &str) -> String {
+    if usage(command).is_none() || !takes_words(command) {
+        return args.trim().to_string();
+    }
+    match tokenize(args) {
+        Ok(words) => words.iter().map(|word| quote(word)).collect::<Vec<String>>().join(" "),
+        Err(_) => args.trim().to_string(),
+    }
+}
+
+/// Split a script into its lines.
+fn parse(script: &str) -> Vec<Line> {
+    let mut result = vec![];
+    let mut lines = script.lines();
+    while let Some(line) = lines.next() {
+        let text = join_continued(line, &mut lines);
+        let line = text.trim();
+        if line.is_empty() {
+            result.push(Line::Blank);
+            continue;
+        }
+        if let Some(text) = line.strip_prefix('#') {
+            result.push(Line::Comment(text.trim().to_string()));
+            continue;
+        }
+
+        let (command, args) = match line.split_once([' ', '\t']) {
+            Some((command, args)) => (command, args),
+            None => (line, ""),
+        };
+        result.push(Line::Instruction(command.to_string(), normalize(command, args)));
+
+        if command == "write-gzip" {
+            for data in lines.by_ref() {
+                let data = data.trim();
+                result.push(Line::Data(data.to_string()));
+                if data == "end-write" {
+                    break;
+                }
+            }
+        }
+    }
+    result
+}
+
+/// This function returns `script` rewritten in the canonical style, for `rapt fmt`.
+/// The script is parsed into its lines, and written back with:
+///
+/// - one space between an instruction and its arguments, and no trailing whitespace
+/// - the words of instructions separated by single spaces, and only quoted when they
+///   have to be, with double quotes: `git-clone https://...` instead of `git-clone "https://..."`
+/// - lines continued with a backslash joined into one line. A backslash at the end
+///   of the script has nothing to continue onto, and is kept for `rapt check` to report
+/// - the `rapt-install` dependencies grouped right after the `package`, `version`,
+///   `description`, and `platforms` lines, with the comments directly above them.
+///   Dependencies written after any other instruction are left where they are, so
+///   they still run after it
+/// - comments written as `# comment`, and at most one empty line in a row
+///
+/// Shell commands, the contents of `write-gzip` blocks, and the manifest header
+/// are kept as they are.
+///
+/// Formatting a script that is already formatted doesn't change it.
+pub fn format_script(script: &str) -> String {
+    let (header, script) = split_header(script);
+    let lines = parse(script);
+
+    // Take the dependencies out, along with the comments directly above them. A
+    // dependency after any other instruction stays where it is, since it may need
+    // what that instruction does, like `UBUNTU sudo apt update`
+    let mut body: Vec<Line> = vec![];
+    let mut dependencies: Vec<Line> = vec![];
+    let mut moved = true;
+    for line in lines {
+        if let Line::Instruction(..) = line {
+            if !line.is_instruction(&HEADER_INSTRUCTIONS) && !line.is_instruction(&["rapt-install"]) {
+                moved = false;
+            }
+        }
+        // A backslash at the end of the script has nothing to continue onto, so the
+        // line is a mistake. It's kept as it is, and it has to stay the last line,
+        // or it would be joined with whatever is written after it
+        if moved && line.is_instruction(&["rapt-install"]) && !is_continued(&line.render()) {
+            let mut comments = vec![];
+            while let Some(Line::Comment(_)) = body.last() {
+                comments.insert(0, body.pop().unwrap());
+            }
+            dependencies.extend(comments);
+            dependencies.push(line);
+        } else {
+            body.push(line);
+        }
+    }
+
+    // Put them back after the lines describing the package
+    if !dependencies.is_empty() {
+        let mut header_end = 0;
+        for (i, line) in body.iter().enumerate() {
+            match line {
+                Line::Blank | Line::Comment(_) => {},
+                line if line.is_instruction(&HEADER_INSTRUCTIONS) && !is_continued(&line.render()) => header_end = i + 1,
+                _ => break,
+            }
+        }
+        let mut group = vec![Line::Blank];
+        group.extend(dependencies);
+        group.push(Line::Blank);
+        body.splice(header_end..header_end, group);
+    }
+
+    // Drop empty lines at the start, the end, and after other empty lines
+    let mut output = String::new();
+    let mut previous = Line::Blank;
+    for line in body {
+        if line == Line::Blank && previous == Line::Blank {
+            continue;
+        }
+        output.push_str(&line.render());
+        output.push('\n');
+        previous = line;
+    }
+    while output.ends_with("\n\n") {
+        output.pop();
+    }
+
+    match header {
+        Some(toml) => {
+            let toml = toml.trim_end();
+            let toml = if toml.is_empty() { String::new() } else { format!("{}\n", toml) };
+            format!("{}\n{}{}\n{}", HEADER_FENCE, toml, HEADER_FENCE, output)
+        },
+        None => output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dependencies_are_grouped_after_the_package() {
+        let script = "package dragon\n# needs git\nrapt-install git\nversion 1.0\n\n\nrapt-install  cmake\n";
+        assert_eq!(format_script(script), "package dragon\nversion 1.0\n\n# needs git\nrapt-install git\nrapt-install cmake\n");
+    }
+
+    #[test]
+    fn dependencies_stay_after_other_instructions() {
+        let script = "package dragon\nrapt-install git\nUBUNTU sudo apt update\nrapt-install cmake\nmkdir build\n";
+        assert_eq!(format_script(script), "package dragon\n\nrapt-install git\n\nUBUNTU sudo apt update\nrapt-install cmake\nmkdir build\n");
+    }
+
+    #[test]
+    fn backslashes_at_the_end_of_the_script_are_kept() {
+        assert_eq!(format_script("package dragon\nmkdir  a \\\n"), "package dragon\nmkdir a \\\n");
+        let script = "package dragon\nmkdir a\n\n# last\nrapt-install  b \\";
+        let formatted = format_script(script);
+        assert_eq!(formatted, "package dragon\nmkdir a\n\n# last\nrapt-install b \\\n");
+        assert_eq!(format_script(&formatted), formatted);
+        assert_eq!(format_script("package dragon\n\nrapt-install b \\\n"), "package dragon\n\nrapt-install b \\\n");
+        assert_eq!(format_script("rapt-install b\nversion \\"), "rapt-install b\n\nversion \\\n");
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        let script = "package dragon\necho hi\n# later\nrapt-install git\nversion 2\n";
+        let formatted = format_script(script);
+        assert_eq!(format_script(&formatted), formatted);
+        assert!(formatted.contains("echo hi\n# later\nrapt-install git\n"));
+    }
+}
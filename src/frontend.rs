@@ -1,47 +1,345 @@
 /// This part of the crate is intended to be a library for other crates.
 /// It supports installing a script from a url, cloning a git repository,
 /// and adding to the user's path
-use crate::platform::Platform;
 use crate::path::PathManager;
 use crate::download::Downloader;
 use crate::backup;
+use crate::jobs::{install_once, lock_package_manager};
+use crate::observer::observer;
+use crate::error::Error;
+use crate::tokenizer::tokenize;
 use crate::input::{input, yes_or_no};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 /// Downloads a script from the given url and runs it.
 /// If there was an error running the install script, 
 /// Ask the user if they want to install using their native
-/// package manager.
-pub fn install(url: String) -> Result<(), String> {
-    if let Ok(mut script) = Downloader::download_script(url.clone()) {
-        println!("Installing rapture script at '{}'", url);
+/// package manager. The package manager installs the package with the
+/// name the script declared, and the user is only asked for a name when
+/// the script doesn't declare one.
+/// 
+/// If the package isn't installed with the package manager either,
+/// the error from the script is returned.
+/// 
+/// A url is only installed once each time rapture runs, so a dependency
+/// shared by several packages isn't downloaded and installed again.
+pub fn install(url: String) -> Result<(), Error> {
+    install_once(&url.clone(), || {
+        let mut script = Downloader::download_script(url.clone())?;
+        observer().install_started(&url);
         match script.run() {
             Ok(_) => Ok(()),
             Err(e) => {
-                println!("There was a problem installing the package: {}", e);
+                // Other installs may be running, so only one of them can ask at a time
+                let prompt = lock_package_manager();
+                observer().message(&format!("There was a problem installing the package: {}", e.root_cause()));
                 let new_manager = backup::installer_name();
-                if yes_or_no(format!("Do you want to try to install your package using {}? (y/n) ", new_manager)) {
-                    let package_name = input(format!("What's the name of the package you want to install with {}? ", new_manager));
-                    backup::install(package_name)?;
+                let question = match script.package_name() {
+                    Some(name) => format!("Do you want to try to install {} using {}? (y/n) ", name, new_manager),
+                    None => format!("Do you want to try to install your package using {}? (y/n) ", new_manager)
+                };
+                if !yes_or_no(question) {
+                    return Err(e);
                 }
-                Ok(())
+
+                let package_name = match script.package_name() {
+                    Some(name) => name,
+                    None => input(format!("What's the name of the package you want to install with {}? ", new_manager))
+                };
+                drop(prompt);
+                if package_name.trim().is_empty() {
+                    return Err(e);
+                }
+                backup::install(package_name.trim().to_string())
             }
         }
-    } else {
+    })
+}
+
+/// The revision of a repository to check out after cloning it.
+#[derive(Clone, PartialEq)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+/// The optional arguments of `git-clone`:
+/// `git-clone <url> [into <dir>] [branch=<name>|tag=<name>|rev=<commit>] [depth=<N>] [submodules]`
+#[derive(Clone, Default)]
+pub struct CloneOptions {
+    /// The directory inside the package to clone into.
+    /// Defaults to the name of the repository, like `git clone` does.
+    pub into: Option<String>,
+    /// The branch, tag, or commit to check out instead of the default branch
+    pub reference: Option<GitRef>,
+    /// Only fetch this many commits of history
+    pub depth: Option<usize>,
+    /// Also clone the repository's submodules
+    pub submodules: bool,
+}
+
+impl CloneOptions {
+    /// Parse the arguments of a `git-clone` instruction into the url and its options.
+    pub fn parse<S: ToString>(args: S) -> Result<(String, Self), Error> {
+        let words = tokenize(&args.to_string())?;
+        let mut words = words.iter().map(|word| word.as_str());
+        let url = match words.next() {
+            Some(url) => url.to_string(),
+            None => return Err(Error::parse("Expected a url of the git repository to clone"))
+        };
+
+        let mut options = Self::default();
+        while let Some(word) = words.next() {
+            let reference = if word == "into" {
+                match words.next() {
+                    Some(dir) => options.into = Some(dir.to_string()),
+                    None => return Err(Error::parse("Expected a directory after 'into'"))
+                }
+                None
+            } else if word == "submodules" {
+                options.submodules = true;
+                None
+            } else if let Some(depth) = word.strip_prefix("depth=") {
+                match depth.parse::<usize>() {
+                    Ok(n) if n > 0 => options.depth = Some(n),
+                    _ => return Err(Error::parse(format!("Invalid clone depth '{}'", depth)))
+                }
+                None
+            } else if let Some(branch) = word.strip_prefix("branch=") {
+                Some(GitRef::Branch(branch.to_string()))
+            } else if let Some(tag) = word.strip_prefix("tag=") {
+                Some(GitRef::Tag(tag.to_string()))
+            } else if let Some(rev) = word.strip_prefix("rev=") {
+                Some(GitRef::Rev(rev.to_string()))
+            } else {
+                return Err(Error::parse(format!("Unrecognized git-clone option '{}'", word)));
+            };
+
+            if reference.is_some() {
+                if options.reference.is_some() {
+                    return Err(Error::parse("Only one of branch=, tag=, or rev= can be given"));
+                }
+                options.reference = reference;
+            }
+        }
+        Ok((url, options))
+    }
+}
+
+/// Get the directory name `git clone` would use for a url,
+/// `https://github.com/user/repo.git` clones into `repo`.
+pub fn repository_name(url: &str) -> Result<String, Error> {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':', '\\'])
+        .next()
+        .unwrap_or("")
+        .trim_end_matches(".git");
+    match name {
+        "" | "." | ".." => Err(Error::parse(format!("Could not get the repository name from '{}', use `into DIR`", url))),
+        name => Ok(name.to_string())
+    }
+}
+
+/// Run git with the given arguments in a directory.
+/// The error contains what git printed to stderr when it exits unsuccessfully.
+fn git(dir: &Path, args: &[&str]) -> Result<(), Error> {
+    let output = match Command::new("git").arg("-C").arg(dir).args(args).output() {
+        Ok(output) => output,
+        Err(e) => return Err(Error::io("Could not run git, is it installed?", e))
+    };
+
+    if output.status.success() {
         Ok(())
+    } else {
+        Err(Error::command_failed(
+            format!("git {}", args.join(" ")),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+        ))
     }
 }
 
 /// Clone a git repository into the installation directory for the package.
-pub fn gitclone(package_name: String, url: String) -> Result<(), String> {
-    let package_dir = PathManager::package_dir(package_name);
-    match Platform::command(format!("cd {}; git clone {}", package_dir, url)) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(format!("Could not clone git repo '{}'", url))
+pub fn gitclone(package_name: String, url: String) -> Result<(), Error> {
+    let destination = PathManager::safe_join(PathManager::package_dir(package_name), &repository_name(&url)?)?;
+    gitclone_with(url, destination, &CloneOptions::default())
+}
+
+/// Clone a git repository into `destination` and check out the revision given in `options`.
+/// If the repository was already cloned there, the revision is fetched and checked out
+/// instead, so running an install script again updates the repository.
+pub fn gitclone_with(url: String, destination: PathBuf, options: &CloneOptions) -> Result<(), Error> {
+    let depth = options.depth.map(|n| format!("--depth={}", n));
+    let depth: Vec<&str> = depth.iter().map(|d| d.as_str()).collect();
+
+    if destination.join(".git").exists() {
+        // Make sure the existing clone is of the same repository
+        git(&destination, &["remote", "set-url", "origin", &url])?;
+        match &options.reference {
+            Some(GitRef::Branch(branch)) => {
+                git(&destination, &[&["fetch"][..], &depth, &["origin", branch]].concat())?;
+                git(&destination, &["checkout", "-B", branch, "FETCH_HEAD"])?;
+            },
+            Some(GitRef::Tag(tag)) | Some(GitRef::Rev(tag)) => {
+                git(&destination, &[&["fetch", "--tags"][..], &depth, &["origin", tag]].concat())?;
+                git(&destination, &["checkout", "--detach", "FETCH_HEAD"])?;
+            },
+            None => {
+                // A shallow clone can't be fast-forwarded, so move to what was fetched
+                git(&destination, &[&["fetch"][..], &depth, &["origin", "HEAD"]].concat())?;
+                git(&destination, &["reset", "--hard", "FETCH_HEAD"])?;
+            },
+        }
+    } else {
+        let parent = match destination.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::permission(format!("Cannot clone into '{}'", destination.display())))
+        };
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            return Err(Error::io(format!("Failed to create directory {}", parent.display()), e));
+        }
+
+        let mut args = vec!["clone"];
+        args.extend(&depth);
+        if let Some(GitRef::Branch(name)) | Some(GitRef::Tag(name)) = &options.reference {
+            args.extend(["--branch", name.as_str()]);
+        }
+        let destination_arg = destination.to_string_lossy().to_string();
+        args.extend(["--", url.as_str(), destination_arg.as_str()]);
+        git(parent, &args)?;
+
+        // A commit can't be cloned directly, so fetch and check it out afterwards
+        if let Some(GitRef::Rev(rev)) = &options.reference {
+            git(&destination, &[&["fetch"][..], &depth, &["origin", rev]].concat())?;
+            git(&destination, &["checkout", "--detach", "FETCH_HEAD"])?;
+        }
+    }
+
+    if options.submodules {
+        git(&destination, &[&["submodule", "update", "--init", "--recursive"][..], &depth].concat())?;
     }
+    Ok(())
 }
 
 /// Add a path to the users path.
 /// This mainly acts a frontend to PathManager::add_to_path.
-pub fn add_to_path(path: String) -> Result<(), String> {
+pub fn add_to_path(path: String) -> Result<(), Error> {
     PathManager::add_to_path(path)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+    use tempfile::{tempdir, TempDir};
+
+    /// Run git in `dir`, with an identity so commits work anywhere.
+    fn run_git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-c", "user.name=rapture", "-c", "user.email=rapture@example.com", "-c", "init.defaultBranch=main"])
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// Commit `version` as the contents of `version.txt`, and return the commit's hash.
+    fn commit(work: &Path, version: &str) -> String {
+        write(work.join("version.txt"), version).unwrap();
+        run_git(work, &["add", "version.txt"]);
+        run_git(work, &["commit", "-m", version]);
+        run_git(work, &["rev-parse", "HEAD"])
+    }
+
+    /// A bare repository with `one` tagged as `v1`, then `two` on `main`,
+    /// and `three` on the `feature` branch. Returns the working copy, the
+    /// url of the bare repository, and the hash of `one`.
+    fn repository(dir: &TempDir) -> (PathBuf, String, String) {
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work).unwrap();
+        run_git(&work, &["init"]);
+        let one = commit(&work, "one");
+        run_git(&work, &["tag", "v1"]);
+        commit(&work, "two");
+        run_git(&work, &["checkout", "-b", "feature"]);
+        commit(&work, "three");
+        run_git(&work, &["checkout", "main"]);
+
+        let bare = dir.path().join("repo.git");
+        run_git(dir.path(), &["clone", "--bare", work.to_str().unwrap(), bare.to_str().unwrap()]);
+        run_git(&work, &["remote", "add", "origin", bare.to_str().unwrap()]);
+        (work, format!("file://{}", bare.display()), one)
+    }
+
+    fn version(destination: &Path) -> String {
+        read_to_string(destination.join("version.txt")).unwrap()
+    }
+
+    fn options(reference: Option<GitRef>) -> CloneOptions {
+        CloneOptions { reference, ..CloneOptions::default() }
+    }
+
+    #[test]
+    fn clones_the_default_branch() {
+        let dir = tempdir().unwrap();
+        let (_, url, _) = repository(&dir);
+        let destination = dir.path().join("package/repo");
+
+        gitclone_with(url, destination.clone(), &options(None)).unwrap();
+        assert_eq!(version(&destination), "two");
+    }
+
+    #[test]
+    fn clones_a_tag_branch_or_rev() {
+        let dir = tempdir().unwrap();
+        let (_, url, one) = repository(&dir);
+
+        let cases = vec![
+            (GitRef::Tag("v1".to_string()), "one"),
+            (GitRef::Branch("feature".to_string()), "three"),
+            (GitRef::Rev(one), "one"),
+        ];
+        for (i, (reference, expected)) in cases.into_iter().enumerate() {
+            let destination = dir.path().join(format!("clone{}", i));
+            gitclone_with(url.clone(), destination.clone(), &options(Some(reference))).unwrap();
+            assert_eq!(version(&destination), expected);
+        }
+    }
+
+    #[test]
+    fn cloning_again_updates_the_repository() {
+        let dir = tempdir().unwrap();
+        let (work, url, _) = repository(&dir);
+        let destination = dir.path().join("repo");
+
+        gitclone_with(url.clone(), destination.clone(), &options(None)).unwrap();
+        commit(&work, "four");
+        run_git(&work, &["push", "origin", "main"]);
+
+        gitclone_with(url.clone(), destination.clone(), &options(None)).unwrap();
+        assert_eq!(version(&destination), "four");
+
+        gitclone_with(url.clone(), destination.clone(), &options(Some(GitRef::Tag("v1".to_string())))).unwrap();
+        assert_eq!(version(&destination), "one");
+
+        gitclone_with(url, destination.clone(), &options(Some(GitRef::Branch("feature".to_string())))).unwrap();
+        assert_eq!(version(&destination), "three");
+    }
+
+    #[test]
+    fn shallow_clones_fetch_one_commit() {
+        let dir = tempdir().unwrap();
+        let (_, url, _) = repository(&dir);
+        let destination = dir.path().join("repo");
+
+        let options = CloneOptions { depth: Some(1), ..CloneOptions::default() };
+        gitclone_with(url, destination.clone(), &options).unwrap();
+        assert_eq!(run_git(&destination, &["rev-list", "--count", "HEAD"]), "1");
+    }
+}
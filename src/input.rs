@@ -1,29 +1,120 @@
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, IsTerminal};
+use std::sync::{Arc, RwLock};
+use crate::observer::{has_observer, observer};
 
-/// This function prompts the user with a message and returns the user's input.
-/// It also pops off trailing carriage returns.
-pub fn input<S: ToString>(prompt: S) -> String {
-    let mut buf = String::new();
-    print!("{}", prompt.to_string());
-    let _ = stdout().flush();
+/// Answers the questions rapture asks during an install. The `rapt` commandline
+/// tool reads answers from the terminal, unless it was given `--yes`, `--no`, or
+/// `--non-interactive`, or stdin isn't a terminal.
+pub trait Prompter: Send + Sync {
+    /// Ask a yes or no question.
+    fn yes_or_no(&self, question: &str) -> bool;
+    /// Ask for some text, or None if the question can't be answered.
+    fn input(&self, question: &str) -> Option<String>;
+    /// Whether the user can answer questions, including the ones asked by the
+    /// commands a script runs. If not, commands can't read from stdin.
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+/// Reads answers from stdin. The questions are shown by the current InstallObserver.
+pub struct StdinPrompter;
+
+impl Prompter for StdinPrompter {
+    fn yes_or_no(&self, question: &str) -> bool {
+        match self.input(question) {
+            Some(response) => response.to_lowercase().trim() == "y",
+            None => false
+        }
+    }
+
+    fn input(&self, question: &str) -> Option<String> {
+        let mut buf = String::new();
+        observer().prompt(question);
 
-    stdin().read_line(&mut buf).expect("Could not get user input");
+        // Nothing can be read once stdin is closed
+        match stdin().read_line(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
 
-    while let Some('\n') = buf.chars().next_back() {
-        buf.pop();
+        while let Some('\n') = buf.chars().next_back() {
+            buf.pop();
+        }
+
+        while let Some('\r') = buf.chars().next_back() {
+            buf.pop();
+        }
+
+        Some(buf)
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
     }
+}
+
+/// Never waits for the user. Every yes or no question gets the same answer,
+/// and every other question goes unanswered.
+pub struct AnswerPrompter {
+    /// The answer to every yes or no question
+    pub answer: bool,
+}
 
-    while let Some('\r') = buf.chars().next_back() {
-        buf.pop();
+impl Prompter for AnswerPrompter {
+    fn yes_or_no(&self, question: &str) -> bool {
+        // Still show the question, so it's clear why something was or wasn't done
+        observer().prompt(question);
+        observer().message(if self.answer { "y" } else { "n" });
+        self.answer
     }
 
-    return buf;
+    fn input(&self, _question: &str) -> Option<String> {
+        None
+    }
+}
+
+/// The prompter every question is asked through.
+static PROMPTER: RwLock<Option<Arc<dyn Prompter>>> = RwLock::new(None);
+
+/// Ask every question from now on through `prompter`.
+pub fn set_prompter(prompter: Arc<dyn Prompter>) {
+    match PROMPTER.write() {
+        Ok(mut current) => *current = Some(prompter),
+        Err(poisoned) => *poisoned.into_inner() = Some(prompter),
+    }
+}
+
+/// Get the prompter questions are asked through. If none was set, questions are
+/// read from stdin when it is a terminal and an observer was set to show them.
+/// Otherwise they are answered with no, instead of waiting for an answer to a
+/// question nobody can see.
+pub fn prompter() -> Arc<dyn Prompter> {
+    let current = match PROMPTER.read() {
+        Ok(current) => current.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    match current {
+        Some(prompter) => prompter,
+        None if stdin().is_terminal() && has_observer() => Arc::new(StdinPrompter),
+        None => Arc::new(AnswerPrompter { answer: false }),
+    }
+}
+
+/// This function prompts the user with a message and returns the user's input.
+/// It also pops off trailing carriage returns. If the question can't be
+/// answered, this returns an empty string.
+pub fn input<S: ToString>(prompt: S) -> String {
+    prompter().input(&prompt.to_string()).unwrap_or_default()
+}
+
+/// Whether the user can answer questions, see Prompter::is_interactive.
+pub fn is_interactive() -> bool {
+    prompter().is_interactive()
 }
 
 /// Used to prompt the user with a yes or no question.
 /// If they answer with Y or y, this function returns true.
 pub fn yes_or_no<S: ToString>(prompt: S) -> bool {
-    let response = input(prompt);
-
-    response.to_lowercase().trim() == "y"
-}
\ No newline at end of file
+    prompter().yes_or_no(&prompt.to_string())
+}
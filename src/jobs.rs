@@ -0,0 +1,193 @@
+/// This part of the crate lets independent parts of an install run at the same time.
+/// A run of consecutive downloads in a script, like the `rapt-install` lines at the
+/// top of dragon.rapt, don't depend on each other, so they are started together
+/// on up to `jobs()` threads. Everything else in a script still runs in order.
+///
+/// The dependencies' own shell commands and `backend-install` lines never run at
+/// the same time, because they may all use the system package manager.
+///
+/// Dependencies shared between subtrees are only installed once: every script url
+/// goes through `install_once`, which makes later installs of the same url wait for
+/// the first one and reuse its result. Dependency cycles are reported as errors
+/// instead of waiting forever.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+use crate::error::Error;
+
+/// The number of steps allowed to run at the same time, set with `rapt install -j`.
+static JOBS: AtomicUsize = AtomicUsize::new(1);
+
+/// Held while the system package manager or a shell command runs, since apt, brew,
+/// and scoop all refuse to run twice at the same time, and shell commands like
+/// `LINUX sudo apt update` may run them.
+static PACKAGE_MANAGER: Mutex<()> = Mutex::new(());
+
+/// Every script url installed during this run of rapture.
+static INSTALLS: Mutex<Option<Installs>> = Mutex::new(None);
+
+/// Signalled whenever an install finishes.
+static FINISHED: Condvar = Condvar::new();
+
+thread_local! {
+    /// The script urls being installed on this thread, outermost first.
+    static ANCESTORS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The state of an install of a script url.
+#[derive(Clone)]
+enum InstallState {
+    Running,
+    Done(Result<(), Error>),
+}
+
+#[derive(Default)]
+struct Installs {
+    states: HashMap<String, InstallState>,
+    /// The urls each running install is waiting on, used to find cycles
+    waiting_on: HashMap<String, HashSet<String>>,
+}
+
+impl Installs {
+    /// Check if `to` can be reached from `from` by following what installs are waiting on.
+    fn reaches(&self, from: &str, to: &str) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from.to_string()];
+        while let Some(url) = stack.pop() {
+            if url == to {
+                return true;
+            }
+            if seen.insert(url.clone()) {
+                if let Some(next) = self.waiting_on.get(&url) {
+                    stack.extend(next.iter().cloned());
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Set the number of steps allowed to run at the same time.
+/// Zero is treated as one.
+pub fn set_jobs(jobs: usize) {
+    JOBS.store(jobs.max(1), Ordering::SeqCst);
+}
+
+/// Get the number of steps allowed to run at the same time.
+pub fn jobs() -> usize {
+    JOBS.load(Ordering::SeqCst)
+}
+
+/// Lock the system package manager until the returned guard is dropped.
+pub fn lock_package_manager() -> MutexGuard<'static, ()> {
+    match PACKAGE_MANAGER.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Lock the installs, creating them on first use.
+fn lock_installs() -> MutexGuard<'static, Option<Installs>> {
+    let mut installs = match INSTALLS.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    installs.get_or_insert_with(Installs::default);
+    installs
+}
+
+/// Run `install` for a script url, unless it was already installed during this run of
+/// rapture. If another thread is installing the same url, this waits for it to finish
+/// and returns its result.
+pub fn install_once<F: FnOnce() -> Result<(), Error>>(url: &str, install: F) -> Result<(), Error> {
+    let parent = ANCESTORS.with(|a| a.borrow().last().cloned());
+    let mut guard = lock_installs();
+
+    if let Some(parent) = &parent {
+        let installs = guard.as_mut().unwrap();
+        // If the url is already waiting on the install that depends on it, neither can finish
+        if installs.reaches(url, parent) {
+            return Err(Error::dependency_message(url, format!("Dependency cycle: '{}' depends on '{}', which depends on it", parent, url)));
+        }
+        installs.waiting_on.entry(parent.clone()).or_default().insert(url.to_string());
+    }
+
+    let state = guard.as_ref().unwrap().states.get(url).cloned();
+    let result = match state {
+        Some(InstallState::Done(result)) => result,
+        Some(InstallState::Running) => {
+            loop {
+                guard = match FINISHED.wait(guard) {
+                    Ok(guard) => guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                if let Some(InstallState::Done(result)) = guard.as_ref().unwrap().states.get(url) {
+                    break result.clone();
+                }
+            }
+        },
+        None => {
+            guard.as_mut().unwrap().states.insert(url.to_string(), InstallState::Running);
+            drop(guard);
+
+            ANCESTORS.with(|a| a.borrow_mut().push(url.to_string()));
+            let result = install();
+            ANCESTORS.with(|a| a.borrow_mut().pop());
+
+            guard = lock_installs();
+            guard.as_mut().unwrap().states.insert(url.to_string(), InstallState::Done(result.clone()));
+            FINISHED.notify_all();
+            result
+        },
+    };
+
+    if let Some(parent) = &parent {
+        if let Some(waiting) = guard.as_mut().unwrap().waiting_on.get_mut(parent) {
+            waiting.remove(url);
+        }
+    }
+    result
+}
+
+/// Run a step for every item on up to `jobs()` threads, and return their results
+/// in the same order as the items. Each thread starts with the script urls being
+/// installed on this thread, so it knows which install it belongs to.
+pub fn run_parallel<T: Sync, F: Fn(&T) -> Result<(), Error> + Sync>(items: &[T], step: F) -> Vec<Result<(), Error>> {
+    let workers = jobs().min(items.len());
+    if workers <= 1 {
+        return items.iter().map(step).collect();
+    }
+
+    let ancestors = ANCESTORS.with(|a| a.borrow().clone());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; items.len()]);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                ANCESTORS.with(|a| *a.borrow_mut() = ancestors.clone());
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let result = step(&items[i]);
+                    if let Ok(mut results) = results.lock() {
+                        results[i] = Some(result);
+                    }
+                }
+            });
+        }
+    });
+
+    let results = match results.into_inner() {
+        Ok(results) => results,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    results
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(Error::io_message("A parallel step did not finish"))))
+        .collect()
+}
@@ -1,4 +1,11 @@
+pub mod error;
 pub mod path;
+pub mod tokenizer;
+pub mod env;
+pub mod registry;
+pub mod jobs;
+pub mod observer;
+pub mod manifest;
 pub mod script;
 pub mod platform;
 pub mod download;
@@ -6,7 +13,11 @@ pub mod backup;
 pub mod input;
 pub mod frontend;
 pub mod capture;
+pub mod check;
+pub mod format;
 
-pub use capture::capture;
+pub use error::Error;
+pub use manifest::Manifest;
+pub use capture::{capture, capture_with, CaptureOptions};
 pub use frontend::*;
 pub use script::*;
\ No newline at end of file
@@ -0,0 +1,254 @@
+/// This part of the crate reads the manifest of a package: what the package is,
+/// as opposed to the steps that install it. A manifest is written in TOML, either
+/// in a header at the top of a script between two `+++` lines:
+///
+/// ```text
+/// +++
+/// name = "dragon"
+/// version = "1.0.0"
+/// description = "A compiler"
+/// dependencies = ["https://raw.githubusercontent.com/adam-mcdaniel/rapture/master/scripts/cmake.rapt"]
+/// binaries = ["dragon/build/dragon"]
+/// +++
+/// git-clone https://github.com/adam-mcdaniel/dragon
+/// ```
+///
+/// or in a `rapture.toml` file next to a script that doesn't have a header.
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// The line a manifest header starts and ends with.
+pub const HEADER_FENCE: &str = "+++";
+
+/// The name of the manifest file next to a script without a header.
+pub const MANIFEST_FILE: &str = "rapture.toml";
+
+/// The metadata of a package. Every field is optional.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// The name of the package. Declaring it is the same as starting the
+    /// script with `package NAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// The urls of the rapture scripts to install before the package,
+    /// like `rapt-install` lines at the top of a script
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+    /// The operating systems the package can be installed on:
+    /// `linux`, `macos`, or `windows`. Empty means every platform.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+    /// The paths of the binaries in the package to link into `~/.rapture/bin`
+    /// after the script runs, like `link-bin` lines at the end of a script
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub binaries: Vec<String>,
+}
+
+impl Manifest {
+    /// This function parses a manifest written in TOML.
+    pub fn parse(toml: &str) -> Result<Self, Error> {
+        match toml::from_str(toml) {
+            Ok(manifest) => Ok(manifest),
+            Err(e) => Err(Error::parse(format!("Invalid manifest: {}", e.message())))
+        }
+    }
+
+    /// This function reads a manifest from a TOML file.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        match read_to_string(path) {
+            Ok(toml) => Self::parse(&toml),
+            Err(e) => Err(Error::io(format!("Could not read the manifest '{}'", path.display()), e))
+        }
+    }
+
+    /// This function reads the `rapture.toml` next to the script at `script_path`,
+    /// if there is one.
+    pub fn companion<P: AsRef<Path>>(script_path: P) -> Result<Option<Self>, Error> {
+        let path = match script_path.as_ref().parent() {
+            Some(dir) => dir.join(MANIFEST_FILE),
+            None => Path::new(MANIFEST_FILE).to_path_buf(),
+        };
+        if path.is_file() {
+            Self::read(path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// This function fills in every field of this manifest that isn't set from `defaults`.
+    pub fn or(mut self, defaults: Manifest) -> Self {
+        self.name = self.name.or(defaults.name);
+        self.version = self.version.or(defaults.version);
+        self.description = self.description.or(defaults.description);
+        self.homepage = self.homepage.or(defaults.homepage);
+        self.license = self.license.or(defaults.license);
+        if self.dependencies.is_empty() {
+            self.dependencies = defaults.dependencies;
+        }
+        if self.platforms.is_empty() {
+            self.platforms = defaults.platforms;
+        }
+        if self.binaries.is_empty() {
+            self.binaries = defaults.binaries;
+        }
+        self
+    }
+
+    /// This function writes the manifest as a header for the top of a script.
+    pub fn to_header(&self) -> Result<String, Error> {
+        match toml::to_string(self) {
+            Ok(toml) => Ok(format!("{}\n{}{}\n", HEADER_FENCE, toml, HEADER_FENCE)),
+            Err(e) => Err(Error::parse(format!("Could not write the manifest: {}", e)))
+        }
+    }
+
+    /// This function describes the manifest as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "version": self.version,
+            "description": self.description,
+            "homepage": self.homepage,
+            "license": self.license,
+            "dependencies": self.dependencies,
+            "platforms": self.platforms,
+            "binaries": self.binaries,
+        })
+    }
+}
+
+/// This function splits a script into the TOML of its manifest header, if it has one,
+/// and the rest of the script. Both are slices of `script`, so line numbers can still
+/// be found from them. A header that is never closed isn't a header.
+pub fn split_header(script: &str) -> (Option<&str>, &str) {
+    let start = script.len() - script.trim_start().len();
+    let rest = &script[start..];
+    let opening = match rest.lines().next() {
+        Some(line) if line.trim() == HEADER_FENCE => line,
+        _ => return (None, script),
+    };
+
+    let after = start + opening.len();
+    let toml_start = match &script[after..] {
+        rest if rest.starts_with("\r\n") => after + 2,
+        rest if rest.starts_with('\n') => after + 1,
+        _ => after,
+    };
+    let mut offset = toml_start;
+    for line in script[toml_start..].split_inclusive('\n') {
+        if line.trim() == HEADER_FENCE {
+            return (Some(&script[toml_start..offset]), &script[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+    (None, script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    fn dragon() -> Manifest {
+        Manifest {
+            name: Some("dragon".to_string()),
+            version: Some("1.0.0".to_string()),
+            description: Some("A \"compiler\"\nfor dragons".to_string()),
+            homepage: None,
+            license: Some("MIT".to_string()),
+            dependencies: vec!["https://example.com/cmake.rapt".to_string()],
+            platforms: vec!["linux".to_string(), "macos".to_string()],
+            binaries: vec!["dragon/build/dragon".to_string()],
+        }
+    }
+
+    #[test]
+    fn manifests_are_parsed() {
+        let manifest = Manifest::parse("name = \"dragon\"\nplatforms = [\"linux\"]\n").unwrap();
+        assert_eq!(manifest.name.as_deref(), Some("dragon"));
+        assert_eq!(manifest.platforms, vec!["linux"]);
+        assert_eq!(manifest.version, None);
+        assert_eq!(Manifest::parse("").unwrap(), Manifest::default());
+    }
+
+    #[test]
+    fn invalid_manifests_are_rejected() {
+        for toml in ["name = ", "nmae = \"dragon\"", "platforms = \"linux\"", "version = 1"] {
+            assert!(matches!(Manifest::parse(toml), Err(Error::Parse(_))), "parsed '{}'", toml);
+        }
+    }
+
+    #[test]
+    fn headers_are_split_from_the_script() {
+        let script = "\n+++\nname = \"dragon\"\n+++\npackage dragon\n";
+        assert_eq!(split_header(script), (Some("name = \"dragon\"\n"), "package dragon\n"));
+        assert_eq!(split_header("+++\r\nname = \"x\"\r\n+++\r\nmkdir a"), (Some("name = \"x\"\r\n"), "mkdir a"));
+        assert_eq!(split_header("+++\n+++\n"), (Some(""), ""));
+        // Without a closing fence, or not at the top, there is no header
+        assert_eq!(split_header("+++\nname = \"x\"\n"), (None, "+++\nname = \"x\"\n"));
+        assert_eq!(split_header("package x\n+++\n+++\n"), (None, "package x\n+++\n+++\n"));
+    }
+
+    #[test]
+    fn headers_are_read_back() {
+        let header = dragon().to_header().unwrap();
+        assert!(header.starts_with("+++\n") && header.ends_with("\n+++\n"), "{}", header);
+        let (toml, body) = split_header(&header);
+        assert_eq!(body, "");
+        assert_eq!(Manifest::parse(toml.unwrap()).unwrap(), dragon());
+
+        assert_eq!(Manifest::default().to_header().unwrap(), "+++\n+++\n");
+    }
+
+    #[test]
+    fn set_fields_win_over_defaults() {
+        let partial = Manifest {
+            name: Some("wyvern".to_string()),
+            platforms: vec!["windows".to_string()],
+            ..Manifest::default()
+        };
+        let merged = partial.or(dragon());
+        assert_eq!(merged.name.as_deref(), Some("wyvern"));
+        assert_eq!(merged.platforms, vec!["windows"]);
+        assert_eq!(merged.version, dragon().version);
+        assert_eq!(merged.dependencies, dragon().dependencies);
+        assert_eq!(merged.binaries, dragon().binaries);
+        assert_eq!(Manifest::default().or(dragon()), dragon());
+        assert_eq!(dragon().or(Manifest::default()), dragon());
+    }
+
+    #[test]
+    fn scripts_without_a_header_use_the_manifest_next_to_them() {
+        let dir = tempdir().unwrap();
+        let script = dir.path().join("dragon.rapt");
+        assert_eq!(Manifest::companion(&script).unwrap(), None);
+
+        write(dir.path().join(MANIFEST_FILE), "name = \"dragon\"\nversion = \"2.0\"\n").unwrap();
+        let companion = Manifest::companion(&script).unwrap().unwrap();
+        assert_eq!(companion.version.as_deref(), Some("2.0"));
+
+        let plain = Script::new("mkdir a\n").with_manifest(companion.clone());
+        assert_eq!(plain.manifest().unwrap(), companion);
+        assert_eq!(plain.package_name().as_deref(), Some("dragon"));
+
+        // A header in the script wins over the file next to it
+        let headed = Script::new("+++\nname = \"wyvern\"\n+++\nmkdir a\n").with_manifest(companion);
+        assert_eq!(headed.manifest().unwrap().name.as_deref(), Some("wyvern"));
+        assert_eq!(headed.manifest().unwrap().version, None);
+    }
+}
@@ -0,0 +1,275 @@
+/// This part of the crate reports what an install is doing. Instead of printing,
+/// the rest of rapture sends events to the current InstallObserver, so programs
+/// embedding rapture can show them however they like: in a GUI, in a log, or not
+/// at all. Nothing is reported until an observer is set with `set_observer`.
+///
+/// The `rapt` commandline tool uses the TerminalObserver, or the JsonObserver
+/// when it is run with `--output json`.
+use serde_json::{json, Value};
+use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use crate::error::Error;
+
+/// A single instruction of a script that is being run.
+#[derive(Clone, Debug)]
+pub struct Step {
+    /// The package the script installs, if it was declared before this step
+    pub package: Option<String>,
+    /// The line of the script the instruction is on, starting at 1
+    pub line: usize,
+    /// The instruction, like `git-clone`
+    pub command: String,
+    /// Everything after the instruction
+    pub args: String,
+}
+
+/// Receives the events of an install. Every method does nothing by default,
+/// so observers only have to implement the events they care about.
+///
+/// Installs can run on several threads at the same time (see jobs), so
+/// events can arrive from any thread.
+pub trait InstallObserver: Send + Sync {
+    /// A script was downloaded from `url` and is about to run.
+    fn install_started(&self, _url: &str) {}
+    /// An instruction of a script is about to run.
+    fn step_started(&self, _step: &Step) {}
+    /// An instruction of a script finished running.
+    fn step_finished(&self, _step: &Step, _result: &Result<(), Error>) {}
+    /// Part of a file was downloaded. `total` is the size of the whole file,
+    /// if it is known. When the download finishes, `downloaded` equals `total`.
+    fn download_progress(&self, _url: &str, _downloaded: u64, _total: Option<u64>) {}
+    /// A directory was added to the user's PATH.
+    fn path_added(&self, _path: &str) {}
+    /// A shell command printed a line to stdout or stderr.
+    fn command_output(&self, _command: &str, _line: &str) {}
+    /// A shell command printed the start of a line, but hasn't finished it yet,
+    /// like a question waiting for an answer. The rest of the line is sent with
+    /// the next `command_output`, or with more of these.
+    fn command_partial_output(&self, _command: &str, _text: &str) {}
+    /// A message for the user, like the ones printed with `echo`.
+    fn message(&self, _message: &str) {}
+    /// The user is about to be asked a question.
+    fn prompt(&self, _question: &str) {}
+}
+
+/// An observer that ignores every event.
+pub struct SilentObserver;
+
+impl InstallObserver for SilentObserver {}
+
+/// An observer that prints every event to the terminal.
+pub struct TerminalObserver;
+
+/// Whether the TerminalObserver printed the start of a command's line without its end.
+static LINE_STARTED: AtomicBool = AtomicBool::new(false);
+
+impl TerminalObserver {
+    /// Show the size of a download in a readable unit.
+    fn size(bytes: u64) -> String {
+        match bytes {
+            b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+            b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+            b => format!("{} B", b),
+        }
+    }
+}
+
+impl InstallObserver for TerminalObserver {
+    fn install_started(&self, url: &str) {
+        println!("Installing rapture script at '{}'", url);
+    }
+
+    fn step_started(&self, step: &Step) {
+        // Only announce the steps that take a while, captured packages
+        // have a step for every file
+        match step.command.as_str() {
+            "git-clone" | "fetch-archive" | "fetch-binary" | "backend-install" => {
+                let package = step.package.clone().unwrap_or_default();
+                println!("==> [{}] {} {}", package, step.command, step.args);
+            },
+            _ => {}
+        }
+    }
+
+    fn download_progress(&self, url: &str, downloaded: u64, total: Option<u64>) {
+        let name = url.rsplit('/').next().unwrap_or(url);
+        match total {
+            Some(total) if downloaded >= total => println!("\r    {} {}", name, Self::size(total)),
+            Some(total) => print!("\r    {} {} / {}", name, Self::size(downloaded), Self::size(total)),
+            None => print!("\r    {} {}", name, Self::size(downloaded)),
+        }
+        let _ = stdout().flush();
+    }
+
+    fn command_output(&self, _command: &str, line: &str) {
+        if LINE_STARTED.swap(false, Ordering::SeqCst) {
+            println!("{}", line);
+        } else {
+            println!("    {}", line);
+        }
+    }
+
+    fn command_partial_output(&self, _command: &str, text: &str) {
+        if LINE_STARTED.swap(true, Ordering::SeqCst) {
+            print!("{}", text);
+        } else {
+            print!("    {}", text);
+        }
+        let _ = stdout().flush();
+    }
+
+    fn message(&self, message: &str) {
+        println!("{}", message);
+    }
+
+    fn prompt(&self, question: &str) {
+        print!("{}", question);
+        let _ = stdout().flush();
+    }
+}
+
+/// An observer that records every event instead of printing it, so the whole
+/// install can be reported as a single JSON object once it is finished.
+#[derive(Default)]
+pub struct JsonObserver {
+    record: Mutex<JsonRecord>,
+}
+
+#[derive(Default)]
+struct JsonRecord {
+    installs: Vec<Value>,
+    steps: Vec<Value>,
+    downloads: Vec<Value>,
+    paths_added: Vec<Value>,
+    output: Vec<Value>,
+    /// The index of the line in `output` a command started without finishing
+    unfinished: Option<usize>,
+    messages: Vec<Value>,
+    prompts: Vec<Value>,
+}
+
+impl JsonRecord {
+    /// Add output of a command, onto the end of its unfinished line if it has one.
+    fn add_output(&mut self, command: &str, text: &str, finished: bool) {
+        match self.unfinished {
+            Some(i) if self.output[i]["command"] == command => {
+                let line = format!("{}{}", self.output[i]["line"].as_str().unwrap_or_default(), text);
+                self.output[i]["line"] = json!(line);
+            },
+            _ => {
+                self.output.push(json!({ "command": command, "line": text }));
+                self.unfinished = Some(self.output.len() - 1);
+            }
+        }
+        if finished {
+            self.unfinished = None;
+        }
+    }
+}
+
+impl JsonObserver {
+    /// Record an event, even if another thread panicked while recording one.
+    fn update<F: FnOnce(&mut JsonRecord)>(&self, f: F) {
+        match self.record.lock() {
+            Ok(mut record) => f(&mut record),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+
+    /// This function returns everything recorded so far as a JSON object.
+    /// Steps are listed in the order they finished, with their result.
+    pub fn record(&self) -> Value {
+        let record = match self.record.lock() {
+            Ok(record) => record,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        json!({
+            "installs": record.installs,
+            "steps": record.steps,
+            "downloads": record.downloads,
+            "paths_added": record.paths_added,
+            "output": record.output,
+            "messages": record.messages,
+            "prompts": record.prompts,
+        })
+    }
+}
+
+impl InstallObserver for JsonObserver {
+    fn install_started(&self, url: &str) {
+        self.update(|r| r.installs.push(json!(url)));
+    }
+
+    fn step_finished(&self, step: &Step, result: &Result<(), Error>) {
+        let mut value = json!({
+            "package": step.package,
+            "line": step.line,
+            "command": step.command,
+            "args": step.args,
+            "success": result.is_ok(),
+        });
+        if let Err(e) = result {
+            value["error"] = e.to_json();
+        }
+        self.update(|r| r.steps.push(value));
+    }
+
+    fn download_progress(&self, url: &str, downloaded: u64, total: Option<u64>) {
+        // Only finished downloads are recorded, progress isn't useful afterwards
+        if let Some(total) = total {
+            if downloaded >= total {
+                self.update(|r| r.downloads.push(json!({ "url": url, "size": total })));
+            }
+        }
+    }
+
+    fn path_added(&self, path: &str) {
+        self.update(|r| r.paths_added.push(json!(path)));
+    }
+
+    fn command_output(&self, command: &str, line: &str) {
+        self.update(|r| r.add_output(command, line, true));
+    }
+
+    fn command_partial_output(&self, command: &str, text: &str) {
+        self.update(|r| r.add_output(command, text, false));
+    }
+
+    fn message(&self, message: &str) {
+        self.update(|r| r.messages.push(json!(message)));
+    }
+
+    fn prompt(&self, question: &str) {
+        self.update(|r| r.prompts.push(json!(question)));
+    }
+}
+
+/// The observer every install reports to.
+static OBSERVER: RwLock<Option<Arc<dyn InstallObserver>>> = RwLock::new(None);
+
+/// Report the events of every install from now on to `observer`.
+pub fn set_observer(observer: Arc<dyn InstallObserver>) {
+    match OBSERVER.write() {
+        Ok(mut current) => *current = Some(observer),
+        Err(poisoned) => *poisoned.into_inner() = Some(observer),
+    }
+}
+
+/// Whether an observer was set with set_observer. Without one, nothing is shown,
+/// including the questions rapture asks.
+pub fn has_observer() -> bool {
+    match OBSERVER.read() {
+        Ok(current) => current.is_some(),
+        Err(poisoned) => poisoned.into_inner().is_some(),
+    }
+}
+
+/// Get the observer installs report to. If none was set, this is a SilentObserver.
+pub fn observer() -> Arc<dyn InstallObserver> {
+    let current = match OBSERVER.read() {
+        Ok(current) => current.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    current.unwrap_or_else(|| Arc::new(SilentObserver))
+}
@@ -2,15 +2,33 @@
 use crate::platform::Platform;
 /// Path manipulations
 use std::path::PathBuf;
-/// For reading/writing files and creating directories
-use std::fs::{OpenOptions, create_dir_all};
-/// We need some basic traits in the IO prelude for File manipulation
-use std::io::prelude::*;
+/// For recording paths in the generated environment files
+use crate::env::EnvManager;
+/// For persisting the path on windows
+use crate::registry::{user_environment, add_to_user_path, remove_from_user_path};
+/// For creating directories and linking binaries
+use std::fs::{create_dir_all, read_link, read_to_string, remove_file, write};
+/// For checking where an existing link in the bin directory points,
+/// and for checking paths given by scripts
+use std::path::{Component, Path};
+/// For resolving the real location of paths given by scripts
+use std::fs::{canonicalize, symlink_metadata};
+/// For reporting what went wrong
+use crate::error::Error;
+/// For reporting the paths that were added
+use crate::observer::observer;
+/// For listing the installed packages and their binaries
+use std::fs::read_dir;
 
 /// The name of the overall install directory where all packages are located.
 /// This affects everything in the PathManager impl.
 const INSTALL_FOLDER_NAME: &str = ".rapture";
 
+/// The name of the directory inside the install directory that holds the links
+/// to every binary a package provides. This is the only directory on the user's PATH
+/// for most packages.
+const BIN_FOLDER_NAME: &str = "bin";
+
 /// Convert a pathbuf to a string
 pub fn path_to_string(p: PathBuf) -> String {
     match p.into_os_string().into_string() {
@@ -40,7 +58,7 @@ impl PathManager {
         let mut install_dir = PathBuf::new();
         install_dir.push(home);
         install_dir.push(INSTALL_FOLDER_NAME);
-        return path_to_string(install_dir);
+        path_to_string(install_dir)
     }
 
     /// This function creates the install directory for all rapture packages.
@@ -48,7 +66,7 @@ impl PathManager {
     /// folder exists so you're able to install packages to that directory.
     /// 
     /// This function must always be run on startup.
-    pub fn make_install_dir() -> Result<(), String> {
+    pub fn make_install_dir() -> Result<(), Error> {
         // the string for the absolute path to install dir
         let install_dir = Self::install_dir();
     
@@ -67,14 +85,31 @@ impl PathManager {
         let mut package_dir = PathBuf::new();
         package_dir.push(install_dir);
         package_dir.push(name);
-        return path_to_string(package_dir);
+        path_to_string(package_dir)
     }
 
 
+    /// This function checks that `name` can be used as the name of a package.
+    /// The package directory is `~/.rapture/NAME`, so a name like `..` or
+    /// `../.ssh` would let a script write anywhere in the user's home directory.
+    /// A name must be a single, normal path component.
+    pub fn check_package_name(name: &str) -> Result<(), Error> {
+        // The bin directory lives next to the packages, so it can't be a package name
+        if name == BIN_FOLDER_NAME {
+            return Err(Error::permission(format!("'{}' is reserved and cannot be used as a package name", name)));
+        }
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(()),
+            _ => Err(Error::permission(format!("'{}' cannot be used as a package name, it must be a single directory name", name)))
+        }
+    }
+
     /// This function makes the installation directory for a specific package,
     /// as opposed to make_install_dir, which creates the directory containing
     /// all rapture's installs.
-    pub fn make_package_dir(name: String) -> Result<(), String> {
+    pub fn make_package_dir(name: String) -> Result<(), Error> {
+        Self::check_package_name(&name)?;
         let package_dir = Self::package_dir(name);
 
         // Always return Ok, create_dir_all fails when the dir already exists
@@ -91,65 +126,320 @@ impl PathManager {
     /// If i call this with `/one/two/three/four`, however, it will add
     /// `/one/two/three/four` to the path.
     /// 
-    /// This is very experimental on windows. This is not guaranteed to work on windows
-    /// platforms at all yet. Full support is intended in the future, however.
-    pub fn add_to_path(name: String) -> Result<(), String> {
-        // The home directory
-        let home = Self::home_dir();
+    /// On linux / macos the path is recorded by the EnvManager, which regenerates
+    /// `~/.rapture/env.sh` and makes sure the user's shell profiles source it.
+    /// On windows the path is added to the user's PATH in the registry.
+    pub fn add_to_path(name: String) -> Result<(), Error> {
+        let package_dir = Self::package_dir(name);
+        // Record the path so the generated environment files include it
+        EnvManager::add_path(package_dir.clone())?;
+
         match Platform::get() {
-            // On linux / macos, edit .bashrc
+            // On linux / macos, make sure the environment file is sourced
             Platform::Unknown | Platform::MacOS | Platform::Ubuntu => {
-                // Start opening the .bashrc file
-                let mut bashrc = PathBuf::new();
-                bashrc.push(home);
-                bashrc.push(".bashrc");
-                let mut file = OpenOptions::new()
-                    .read(true)
-                    .append(true)
-                    .open(bashrc.clone());
-                // Finish opening the .bashrc file
-
-                // Verify file is good
-                match &mut file {
-                    Ok(f) => {
-                        // If file is good, get the absolute path of the directory
-                        // we will add the the users path in .bashrc
-                        let package_dir = Self::package_dir(name);
-                        // Make a fmt string to write to the file
-                        let path_addition = format!("export PATH=\"$PATH:{}\"", package_dir);
-
-                        // Read bashrc to a string
-                        let mut contents = String::new();
-                        f.read_to_string(&mut contents).unwrap();
-
-                        // If bashrc contains the line already, return and do nothing
-                        for line in contents.lines() {
-                            if line.to_string().contains(&path_addition) {
-                                return Ok(());
-                            }
-                        }
-                        
-                        // If bashrc doesnt have the path already, write it.
-                        match writeln!(f, "{}", path_addition) {
-                            Ok(_) => Ok(()),
-                            Err(_) => Err(format!("Failed to append to file {}", path_to_string(bashrc)))
-                        }
-                    },
-                    Err(_) => Err(format!("Failed to add {} to PATH", name))
-                }
+                EnvManager::source_from_profile()?;
             },
 
-            // Windows support is very minimal.
-            // I dont know if this works, but set path using the set command?
+            // On windows, persist the path in the registry
             Platform::Windows => {
-                let package_dir = Self::package_dir(name);
-                let path_addition = format!("set PATH=%PATH%;{}", package_dir.clone());
-
-                // Because i dont know if this works, print the path so the user can
-                // add the path if it is necessary.
-                println!("WARNING: modifying the path on windows is experimental. If this install does not seem to work, add '{}' to your path environment variable.", package_dir);
-                Platform::command(format!("{}", path_addition))
+                let _lock = EnvManager::lock()?;
+                add_to_user_path(user_environment()?.as_mut(), &package_dir)?;
             },
         }
+        observer().path_added(&package_dir);
+        Ok(())
+    }
+
+    /// This function removes a path added with add_to_path from the user's path.
+    /// Removing a path that was never added does nothing.
+    pub fn remove_from_path(name: String) -> Result<(), Error> {
+        let package_dir = Self::package_dir(name);
+        EnvManager::remove_path(package_dir.clone())?;
+
+        if Platform::get() == Platform::Windows {
+            let _lock = EnvManager::lock()?;
+            remove_from_user_path(user_environment()?.as_mut(), &package_dir)?;
+        }
+        Ok(())
+    }
+
+    /// This function joins a path given by a script onto a directory, like the package
+    /// installation directory, and makes sure the result stays inside that directory.
+    /// Every instruction that touches files goes through this function.
+    /// 
+    /// Absolute paths and paths containing `..` are rejected. The result is also
+    /// resolved through any symlinks that already exist, so a symlink inside the
+    /// directory can't be used to reach a file outside of it. Symlinks that don't
+    /// point anywhere are rejected too, because writing through them would create
+    /// their target wherever it is.
+    pub fn safe_join<S: AsRef<Path>>(base: S, path: &str) -> Result<PathBuf, Error> {
+        let base = base.as_ref();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(_) | Component::CurDir => {},
+                _ => return Err(Error::permission(format!("The path '{}' must be relative to '{}' and cannot contain '..'", path, base.display())))
+            }
+        }
+
+        let joined = base.join(path);
+        let real_base = match canonicalize(base) {
+            Ok(b) => b,
+            Err(e) => return Err(Error::io(format!("Could not resolve the directory '{}'", base.display()), e))
+        };
+
+        // Find the deepest part of the path that already exists, and resolve it
+        let mut existing = joined.clone();
+        while symlink_metadata(&existing).is_err() && existing.pop() {}
+        match canonicalize(&existing) {
+            Ok(real) if real.starts_with(&real_base) => Ok(joined),
+            Ok(_) => Err(Error::permission(format!("The path '{}' points outside of '{}'", path, base.display()))),
+            Err(e) => Err(Error::io(format!("Could not resolve the path '{}' in '{}'", path, base.display()), e)),
+        }
+    }
+
+    /// This function returns the absolute path to the directory holding the links
+    /// to the binaries provided by packages, `~/.rapture/bin`.
+    pub fn bin_dir() -> String {
+        let mut bin_dir = PathBuf::new();
+        bin_dir.push(Self::install_dir());
+        bin_dir.push(BIN_FOLDER_NAME);
+        path_to_string(bin_dir)
+    }
+
+    /// This function creates the bin directory and makes sure it is on the user's path.
+    /// Adding the bin directory to the path more than once does nothing.
+    pub fn make_bin_dir() -> Result<(), Error> {
+        match create_dir_all(Self::bin_dir()) {
+            Ok(_) => Self::add_to_path(Self::bin_dir()),
+            Err(e) => Err(Error::io(format!("Failed to create directory {}", Self::bin_dir()), e))
+        }
+    }
+
+    /// This function links a binary inside a package's installation directory into
+    /// the bin directory. For example, if i call this function with `build/tool` in a
+    /// package named `wonderful`, `~/.rapture/bin/tool` will point to
+    /// `/home/user/.rapture/wonderful/build/tool`. The link can be given a different
+    /// name with `link_name`.
+    /// 
+    /// On windows, a `.cmd` shim script is created instead of a symlink, because
+    /// creating symlinks requires administrator privileges there.
+    /// 
+    /// If another package already provides a binary with the same name, this
+    /// returns an error instead of replacing it.
+    pub fn link_bin(package_name: String, path: String, link_name: Option<String>) -> Result<(), Error> {
+        if let Some(name) = &link_name {
+            Self::check_link_name(name)?;
+        }
+        let target = Self::safe_join(Self::package_dir(package_name.clone()), &path)?;
+        // A link to nothing would only fail later, when the binary is run
+        if !target.exists() {
+            return Err(Error::io_message(format!("Could not link '{}', '{}' doesn't exist", path, path_to_string(target))));
+        }
+
+        // Default to the file name of the binary
+        let link_name = match link_name {
+            Some(name) => name,
+            None => match target.file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => return Err(Error::parse(format!("Could not get the binary name from '{}'", path)))
+            }
+        };
+
+        Self::make_bin_dir()?;
+        let mut link = PathBuf::new();
+        link.push(Self::bin_dir());
+        match Platform::get() {
+            Platform::Windows => link.push(format!("{}.cmd", link_name)),
+            _ => link.push(link_name.clone()),
+        }
+
+        // If the link already exists, find out which package provides it
+        if let Some(existing) = Self::linked_target(&link) {
+            if existing == target {
+                return Ok(());
+            }
+            match Self::owning_package(&existing) {
+                Some(owner) if owner != package_name => {
+                    return Err(Error::permission(format!("Could not link '{}', it is already provided by package '{}'", link_name, owner)))
+                },
+                Some(_) => {},
+                None => return Err(Error::permission(format!("Could not link '{}', '{}' already exists", link_name, path_to_string(link))))
+            }
+            if let Err(e) = remove_file(link.clone()) {
+                return Err(Error::io(format!("Failed to remove old link {}", path_to_string(link)), e));
+            }
+        }
+
+        let result = match Platform::get() {
+            Platform::Windows => write(link.clone(), format!("@echo off\r\n\"{}\" %*\r\n", path_to_string(target.clone()))),
+            _ => Self::symlink(&target, &link),
+        };
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::io(format!("Failed to link {} to {}", path_to_string(link), path_to_string(target)), e))
+        }
+    }
+
+    /// This function checks that `name` can be used as the name of a link in the
+    /// bin directory. Like a package name, it must be a single, normal path component,
+    /// so `link-bin tool as ../../evil` can't create a file outside of the bin directory.
+    pub fn check_link_name(name: &str) -> Result<(), Error> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(()),
+            _ => Err(Error::permission(format!("'{}' cannot be used as the name of a binary, it must be a single file name", name)))
+        }
+    }
+
+    /// This function returns the names of every installed package, which are
+    /// the directories in the install directory, sorted by name.
+    pub fn installed_packages() -> Vec<String> {
+        let mut packages = vec![];
+        if let Ok(entries) = read_dir(Self::install_dir()) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.path().is_dir() && name != BIN_FOLDER_NAME {
+                    packages.push(name);
+                }
+            }
+        }
+        packages.sort();
+        packages
+    }
+
+    /// This function returns every binary linked into the bin directory, with the
+    /// path it points to and the package that provides it, sorted by name.
+    pub fn linked_binaries() -> Vec<(String, PathBuf, Option<String>)> {
+        let mut binaries = vec![];
+        if let Ok(entries) = read_dir(Self::bin_dir()) {
+            for entry in entries.flatten() {
+                let link = entry.path();
+                if let Some(target) = Self::linked_target(&link) {
+                    let name = entry.file_name().to_string_lossy().trim_end_matches(".cmd").to_string();
+                    let package = Self::owning_package(&target);
+                    binaries.push((name, target, package));
+                }
+            }
+        }
+        binaries.sort();
+        binaries
+    }
+
+    /// Get the binary an existing link or shim in the bin directory points to.
+    fn linked_target(link: &Path) -> Option<PathBuf> {
+        if let Ok(target) = read_link(link) {
+            return Some(target);
+        }
+
+        // Shims are `.cmd` files with the quoted target on their second line
+        let shim = read_to_string(link).ok()?;
+        let line = shim.lines().nth(1)?;
+        let target = line.split('"').nth(1)?;
+        Some(PathBuf::from(target))
+    }
+
+    /// Get the name of the package whose installation directory contains `path`.
+    fn owning_package(path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(Self::install_dir()).ok()?;
+        relative.components().next().map(|c| c.as_os_str().to_string_lossy().to_string())
+    }
+
+    #[cfg(unix)]
+    fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    // Other platforms use shim scripts instead of symlinks
+    #[cfg(not(unix))]
+    fn symlink(_: &Path, _: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "symlinks are not supported on this platform"))
+    }
+}
+
+/// Held by the tests that change the home directory, which is shared by the whole process.
+#[cfg(test)]
+static HOME_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// This function points the home directory at a new temporary directory, for
+/// tests that install packages. These tests run one at a time, each keeps the
+/// home directory until it drops the returned guard.
+#[cfg(test)]
+pub(crate) fn temp_home() -> (std::sync::MutexGuard<'static, ()>, tempfile::TempDir) {
+    let guard = HOME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let home = tempfile::tempdir().unwrap();
+    std::env::set_var("HOME", home.path());
+    (guard, home)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn package_names_cannot_escape_the_install_dir() {
+        for name in ["", ".", "..", "../.ssh", "a/b", "a\\b", "/etc", "bin"] {
+            assert!(PathManager::check_package_name(name).is_err(), "accepted '{}'", name);
+            assert!(PathManager::make_package_dir(name.to_string()).is_err(), "made '{}'", name);
+        }
+    }
+
+    #[test]
+    fn package_names_can_be_single_directories() {
+        for name in ["dragon", "rusty-ci", "python3.8", ".hidden", "a..b"] {
+            assert!(PathManager::check_package_name(name).is_ok(), "rejected '{}'", name);
+        }
+    }
+
+    #[test]
+    fn link_names_cannot_escape_the_bin_dir() {
+        for name in ["", ".", "..", "../../evil", "a/b", "a\\b", "/usr/bin/evil"] {
+            assert!(PathManager::check_link_name(name).is_err(), "accepted '{}'", name);
+            match PathManager::link_bin("pkg".to_string(), "tool".to_string(), Some(name.to_string())) {
+                Err(Error::Permission(message)) => assert!(message.contains("name of a binary"), "{}", message),
+                _ => panic!("linked '{}'", name),
+            }
+        }
+        assert!(PathManager::check_link_name("tool-2").is_ok());
+    }
+
+    #[test]
+    fn missing_binaries_are_not_linked() {
+        let (_guard, home) = temp_home();
+        PathManager::make_package_dir("pkg".to_string()).unwrap();
+        match PathManager::link_bin("pkg".to_string(), "build/tool".to_string(), None) {
+            Err(e @ Error::Io { .. }) => assert!(e.to_string().contains("build/tool"), "{}", e),
+            other => panic!("{:?}", other),
+        }
+        let link = home.path().join(".rapture").join("bin").join("tool");
+        assert!(symlink_metadata(&link).is_err());
+
+        let package = home.path().join(".rapture").join("pkg");
+        create_dir_all(package.join("build")).unwrap();
+        write(package.join("build/tool"), "").unwrap();
+        PathManager::link_bin("pkg".to_string(), "build/tool".to_string(), None).unwrap();
+        #[cfg(unix)]
+        assert_eq!(read_link(link).unwrap(), package.join("build/tool"));
+    }
+
+    #[test]
+    fn safe_join_rejects_paths_outside_the_base() {
+        let base = tempdir().unwrap();
+        for path in ["..", "../x", "a/../../x", "/etc/passwd"] {
+            assert!(PathManager::safe_join(base.path(), path).is_err(), "joined '{}'", path);
+        }
+        assert_eq!(PathManager::safe_join(base.path(), "a/./b").unwrap(), base.path().join("a/./b"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn safe_join_rejects_symlinks_out_of_the_base() {
+        let base = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), base.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("missing"), base.path().join("dangling")).unwrap();
+        assert!(PathManager::safe_join(base.path(), "link/file").is_err());
+        assert!(PathManager::safe_join(base.path(), "dangling").is_err());
     }
 }
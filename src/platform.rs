@@ -1,8 +1,31 @@
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::fmt::Display;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use crate::observer::observer;
+use crate::error::Error;
+use crate::input::is_interactive;
 use os_info::{get, Type};
 
 
+/// The names of the platforms a package can declare it supports,
+/// with `platforms` in a script or in its manifest.
+pub const PLATFORM_NAMES: [&str; 3] = ["linux", "macos", "windows"];
+
+/// Whether to install packages on platforms they don't support,
+/// set with `rapt install --ignore-platform`.
+static IGNORE_PLATFORM: AtomicBool = AtomicBool::new(false);
+
+/// Set whether packages are installed on platforms they don't declare support for.
+pub fn set_ignore_platform(ignore: bool) {
+    IGNORE_PLATFORM.store(ignore, Ordering::SeqCst);
+}
+
+/// Whether packages are installed on platforms they don't declare support for.
+pub fn ignore_platform() -> bool {
+    IGNORE_PLATFORM.load(Ordering::SeqCst)
+}
 
 /// This enumeration is used for detecting the user's operating system and
 /// for writing things to the commandline. It automatically switches formats
@@ -32,48 +55,109 @@ impl Platform {
         }
     }
 
+    /// This returns the name of the operating system used in download urls,
+    /// like the `{os}` placeholder of `fetch-binary`. Every platform that
+    /// isn't windows or macos is assumed to be linux.
+    pub fn os_name(&self) -> &'static str {
+        match self {
+            Platform::Windows => "windows",
+            Platform::MacOS => "macos",
+            Platform::Ubuntu | Platform::Unknown => "linux",
+        }
+    }
+
+    /// This returns the name of the cpu architecture rapture was built for,
+    /// like `x86_64` or `aarch64`.
+    pub fn arch() -> &'static str {
+        std::env::consts::ARCH
+    }
+
     /// This function writes a command the operating system's respective
     /// command line shell.
     /// 
-    /// For now, there is a bug with error detection. Because Im calling
-    /// bash / sh with a string containing the script instruction to run, 
-    /// the stderr doesnt get processed properly by std::process::Command.
-    /// 
-    /// I will try to fix this bug in the future because its causing the program
-    /// to continue as if its succeeding, even if its hit a catastrophic failure.
-    pub fn command<S: Display>(s: S) -> Result<(), String> {
+    /// If the command exits unsuccessfully, this returns Error::CommandFailed
+    /// with its exit status, so a failing step stops the install.
+    pub fn command<S: Display>(s: S) -> Result<(), Error> {
         // Trim extraneous semicolons, bash / sh doesnt like it one bit!
         let s = s.to_string().trim_end_matches(";").to_string();
-        match Self::get() {
+        let platform = Self::get();
+        let (shell, flag, name) = match platform {
             // Run a command on CMD
-            Platform::Windows => {
-                match Command::new("cmd").args(&["/C", &format!("{}", s)]).output() {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(format!("Failed to run Windows command '{}'", s))
-                }
-            },
-
-            // Run a command on bash
-            Platform::MacOS => {
-                match Command::new("bash").args(&["-c", &format!("{}", s)]).output() {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(format!("Failed to run MacOS command '{}'", s))
-                }
-            },
+            Platform::Windows => ("cmd", "/C", "Windows"),
             // Run a command on bash
-            Platform::Ubuntu => {
-                match Command::new("bash").args(&["-c", &format!("{}", s)]).output() {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(format!("Failed to run Ubuntu command '{}'", s))
-                }
-            },
+            Platform::MacOS => ("bash", "-c", "MacOS"),
+            Platform::Ubuntu => ("bash", "-c", "Ubuntu"),
             // Because it's not known if `Unknown` has bash, use more widespread sh shell.
-            Platform::Unknown => {
-                match Command::new("sh").args(&["-c", &format!("{}", s)]).output() {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(format!("Failed to run Linux command '{}'", s))
-                }
+            Platform::Unknown => ("sh", "-c", "Linux"),
+        };
+
+        let mut child = match Command::new(shell)
+            .args([flag, &s])
+            // A command waiting for an answer would hang forever if nobody can give one
+            .stdin(if is_interactive() { Stdio::inherit() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => return Err(Error::io(format!("Failed to run {} command '{}'", name, s), e))
+        };
+
+        // Send everything the command prints to the observer as it is printed
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        thread::scope(|scope| {
+            if let Some(stdout) = stdout {
+                scope.spawn(|| Self::forward_output(&s, stdout));
+            }
+            if let Some(stderr) = stderr {
+                scope.spawn(|| Self::forward_output(&s, stderr));
+            }
+        });
+
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(Error::command_failed(s, status.code(), "")),
+            Err(e) => Err(Error::io(format!("Failed to run {} command '{}'", name, s), e))
+        }
+    }
+
+    /// Send each line read from a command's output to the observer. Output that
+    /// doesn't end a line, like `Continue? [Y/n] `, is sent as soon as it is read,
+    /// because the command may be waiting for an answer to it.
+    fn forward_output<R: Read>(command: &str, mut output: R) {
+        let mut buf = [0; 4096];
+        let mut pending: Vec<u8> = vec![];
+        // Whether the start of a line was sent without its end
+        let mut started = false;
+        loop {
+            match output.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => pending.extend_from_slice(&buf[..n]),
             }
+
+            while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let text = String::from_utf8_lossy(&line);
+                observer().command_output(command, text.trim_end_matches(['\r', '\n']));
+                started = false;
+            }
+
+            // Keep a character that was only partly read until the rest of it arrives
+            let complete = match std::str::from_utf8(&pending) {
+                Ok(_) => pending.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(_) => pending.len(),
+            };
+            if complete > 0 {
+                let text: Vec<u8> = pending.drain(..complete).collect();
+                observer().command_partial_output(command, &String::from_utf8_lossy(&text));
+                started = true;
+            }
+        }
+
+        // The command finished without ending its last line, so end it here
+        if started || !pending.is_empty() {
+            observer().command_output(command, &String::from_utf8_lossy(&pending));
         }
     }
 }
\ No newline at end of file
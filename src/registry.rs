@@ -0,0 +1,270 @@
+/// This part of the crate persists changes to the user's environment on windows.
+/// Windows doesn't have a profile script like `.bashrc`, instead the user's PATH and
+/// environment variables live in the `HKCU\Environment` registry key. After changing
+/// the key, running programs like explorer are told to reload their environment
+/// with a WM_SETTINGCHANGE broadcast, so new shells see the changes.
+///
+/// The registry is accessed through the Registry trait so the PATH logic can run
+/// against MemoryRegistry on platforms without a registry.
+use std::collections::HashMap;
+use crate::error::Error;
+
+/// The name of the registry value holding the user's PATH.
+const PATH_NAME: &str = "Path";
+
+/// The separator between directories in a windows PATH.
+const PATH_SEPARATOR: char = ';';
+
+/// The operations rapture needs from the registry's user environment.
+/// Value names are case insensitive, like they are in the real registry.
+pub trait Registry {
+    /// Get the value of a variable, or None if it isn't set.
+    fn get(&self, name: &str) -> Result<Option<String>, Error>;
+    /// Set the value of a variable, replacing the old value.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Error>;
+    /// Tell running programs that the environment changed.
+    fn broadcast(&mut self) -> Result<(), Error>;
+}
+
+/// An in memory registry. This behaves like the user's environment in the
+/// registry without touching the real one, which is useful for testing.
+#[derive(Default)]
+pub struct MemoryRegistry {
+    values: HashMap<String, String>,
+    /// The number of times a change was broadcast
+    pub broadcasts: usize,
+}
+
+impl MemoryRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn get(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self.values.get(&name.to_lowercase()).cloned())
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        self.values.insert(name.to_lowercase(), value.to_string());
+        Ok(())
+    }
+
+    fn broadcast(&mut self) -> Result<(), Error> {
+        self.broadcasts += 1;
+        Ok(())
+    }
+}
+
+/// The current user's environment in the windows registry, `HKCU\Environment`.
+#[cfg(windows)]
+pub struct UserEnvironment {
+    key: winreg::RegKey,
+}
+
+#[cfg(windows)]
+impl Registry for UserEnvironment {
+    fn get(&self, name: &str) -> Result<Option<String>, Error> {
+        match self.key.get_value::<String, _>(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::io(format!("Could not read '{}' from the registry", name), e)),
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        use winreg::enums::REG_EXPAND_SZ;
+        use winreg::RegValue;
+
+        // Values are written as REG_EXPAND_SZ so entries like `%USERPROFILE%\bin` keep working
+        let mut bytes = vec![];
+        for unit in value.encode_utf16().chain(std::iter::once(0)) {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+
+        match self.key.set_raw_value(name, &RegValue { bytes, vtype: REG_EXPAND_SZ }) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::io(format!("Could not write '{}' to the registry", name), e)),
+        }
+    }
+
+    fn broadcast(&mut self) -> Result<(), Error> {
+        use winapi::shared::minwindef::LPARAM;
+        use winapi::um::winuser::{SendMessageTimeoutW, HWND_BROADCAST, SMTO_ABORTIFHUNG, WM_SETTINGCHANGE};
+
+        let area: Vec<u16> = "Environment".encode_utf16().chain(std::iter::once(0)).collect();
+        let mut result = 0;
+        let sent = unsafe {
+            SendMessageTimeoutW(
+                HWND_BROADCAST,
+                WM_SETTINGCHANGE,
+                0,
+                area.as_ptr() as LPARAM,
+                SMTO_ABORTIFHUNG,
+                5000,
+                &mut result,
+            )
+        };
+
+        match sent {
+            0 => Err(Error::io_message("Failed to notify running programs that the environment changed")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Open the current user's environment in the registry.
+/// Only windows has a registry, so this fails on every other platform.
+#[cfg(windows)]
+pub fn user_environment() -> Result<Box<dyn Registry>, Error> {
+    use winreg::enums::{HKEY_CURRENT_USER, KEY_READ, KEY_WRITE};
+    use winreg::RegKey;
+
+    match RegKey::predef(HKEY_CURRENT_USER).open_subkey_with_flags("Environment", KEY_READ | KEY_WRITE) {
+        Ok(key) => Ok(Box::new(UserEnvironment { key })),
+        Err(e) => Err(Error::io("Could not open the user environment in the registry", e)),
+    }
+}
+
+/// Open the current user's environment in the registry.
+/// Only windows has a registry, so this fails on every other platform.
+#[cfg(not(windows))]
+pub fn user_environment() -> Result<Box<dyn Registry>, Error> {
+    Err(Error::io_message("The registry is only available on windows"))
+}
+
+/// Compare two directories the way windows does: case insensitively,
+/// and ignoring trailing slashes.
+fn same_dir(a: &str, b: &str) -> bool {
+    let normalize = |s: &str| s.trim_end_matches(['\\', '/']).to_lowercase();
+    normalize(a) == normalize(b)
+}
+
+/// Get the directories on the user's PATH, without empty entries or duplicates.
+fn user_path(registry: &dyn Registry) -> Result<Vec<String>, Error> {
+    let path = registry.get(PATH_NAME)?.unwrap_or_default();
+    let mut dirs: Vec<String> = vec![];
+    for dir in path.split(PATH_SEPARATOR) {
+        if !dir.is_empty() && !dirs.iter().any(|d| same_dir(d, dir)) {
+            dirs.push(dir.to_string());
+        }
+    }
+    Ok(dirs)
+}
+
+/// Write the user's PATH and broadcast the change.
+fn set_user_path(registry: &mut dyn Registry, dirs: Vec<String>) -> Result<(), Error> {
+    registry.set(PATH_NAME, &dirs.join(&PATH_SEPARATOR.to_string()))?;
+    registry.broadcast()
+}
+
+/// Append a directory to the user's PATH.
+/// Returns false if the directory was already on the PATH, in which case nothing is written.
+pub fn add_to_user_path(registry: &mut dyn Registry, dir: &str) -> Result<bool, Error> {
+    let mut dirs = user_path(registry)?;
+    if dirs.iter().any(|d| same_dir(d, dir)) {
+        return Ok(false);
+    }
+
+    dirs.push(dir.to_string());
+    set_user_path(registry, dirs)?;
+    Ok(true)
+}
+
+/// Remove a directory from the user's PATH.
+/// Returns false if the directory wasn't on the PATH, in which case nothing is written.
+pub fn remove_from_user_path(registry: &mut dyn Registry, dir: &str) -> Result<bool, Error> {
+    let dirs = user_path(registry)?;
+    let remaining: Vec<String> = dirs.iter().filter(|d| !same_dir(d, dir)).cloned().collect();
+    if remaining.len() == dirs.len() {
+        return Ok(false);
+    }
+
+    set_user_path(registry, remaining)?;
+    Ok(true)
+}
+
+/// Set an environment variable for the user and broadcast the change.
+pub fn set_user_var(registry: &mut dyn Registry, name: &str, value: &str) -> Result<(), Error> {
+    registry.set(name, value)?;
+    registry.broadcast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(registry: &MemoryRegistry) -> String {
+        registry.get(PATH_NAME).unwrap().unwrap_or_default()
+    }
+
+    #[test]
+    fn add_appends_to_the_path() {
+        let mut registry = MemoryRegistry::new();
+        registry.set("PATH", r"C:\Windows;C:\Tools").unwrap();
+
+        assert!(add_to_user_path(&mut registry, r"C:\Users\me\.rapture\bin").unwrap());
+        assert_eq!(path(&registry), r"C:\Windows;C:\Tools;C:\Users\me\.rapture\bin");
+        assert_eq!(registry.broadcasts, 1);
+    }
+
+    #[test]
+    fn add_creates_a_missing_path() {
+        let mut registry = MemoryRegistry::new();
+        assert!(add_to_user_path(&mut registry, r"C:\Tools").unwrap());
+        assert_eq!(path(&registry), r"C:\Tools");
+    }
+
+    #[test]
+    fn add_ignores_directories_already_on_the_path() {
+        let mut registry = MemoryRegistry::new();
+        registry.set(PATH_NAME, r"C:\Windows;C:\Tools\").unwrap();
+
+        for dir in [r"C:\Tools", r"c:\tools", r"C:\TOOLS\", "C:\\Tools/", r"C:\Windows"] {
+            assert!(!add_to_user_path(&mut registry, dir).unwrap(), "added '{}' again", dir);
+        }
+        assert_eq!(path(&registry), r"C:\Windows;C:\Tools\");
+        assert_eq!(registry.broadcasts, 0);
+    }
+
+    #[test]
+    fn add_drops_duplicates_and_empty_entries() {
+        let mut registry = MemoryRegistry::new();
+        registry.set(PATH_NAME, r"C:\Windows;;c:\windows\;C:\Tools;").unwrap();
+
+        assert!(add_to_user_path(&mut registry, r"C:\Bin").unwrap());
+        assert_eq!(path(&registry), r"C:\Windows;C:\Tools;C:\Bin");
+    }
+
+    #[test]
+    fn remove_takes_a_directory_off_the_path() {
+        let mut registry = MemoryRegistry::new();
+        registry.set(PATH_NAME, r"C:\Windows;C:\Tools\;C:\Bin").unwrap();
+
+        assert!(remove_from_user_path(&mut registry, r"c:\tools").unwrap());
+        assert_eq!(path(&registry), r"C:\Windows;C:\Bin");
+        assert_eq!(registry.broadcasts, 1);
+    }
+
+    #[test]
+    fn remove_ignores_directories_not_on_the_path() {
+        let mut registry = MemoryRegistry::new();
+        registry.set(PATH_NAME, r"C:\Windows").unwrap();
+
+        assert!(!remove_from_user_path(&mut registry, r"C:\Tools").unwrap());
+        assert_eq!(path(&registry), r"C:\Windows");
+        assert_eq!(registry.broadcasts, 0);
+    }
+
+    #[test]
+    fn set_var_broadcasts_every_change() {
+        let mut registry = MemoryRegistry::new();
+        set_user_var(&mut registry, "GOPATH", r"C:\go").unwrap();
+        set_user_var(&mut registry, "gopath", r"D:\go").unwrap();
+
+        assert_eq!(registry.get("GOPATH").unwrap().as_deref(), Some(r"D:\go"));
+        assert_eq!(registry.broadcasts, 2);
+    }
+}
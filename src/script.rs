@@ -1,12 +1,19 @@
 use crate::path::{PathManager, path_to_string};
-use crate::platform::Platform;
+use crate::env::EnvManager;
+use crate::platform::{ignore_platform, Platform, PLATFORM_NAMES};
 use crate::backup;
-use crate::frontend::{install, gitclone, add_to_path};
-use crate::capture::decode;
-use std::fmt::{Display, Formatter, Error};
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::prelude::*;
-use std::path::PathBuf;
+use crate::frontend::{install, gitclone_with, repository_name, add_to_path, CloneOptions};
+use crate::download::{Downloader, ArchiveOptions, BinaryOptions};
+use crate::capture::{write_hex, write_gzip, set_mode, set_mtime, make_symlink};
+use std::fmt::{self, Display, Formatter};
+use crate::jobs::{lock_package_manager, run_parallel};
+use crate::observer::{observer, Step};
+use crate::error::Error;
+use crate::tokenizer::{tokenize, join_continued};
+use crate::manifest::{split_header, Manifest};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::str::Lines;
 
 /// This object represents the core of the installation process.
 /// In the future, I would like to make the scripts more complex.
@@ -25,46 +32,236 @@ pub struct Script {
     // script does not.
     package_name: Option<String>,
 
+    // The version and description of the package, if the script declares them
+    // with the `version` and `description` rapture commands.
+    version: Option<String>,
+    description: Option<String>,
+
+    // The manifest read from a `rapture.toml` next to the script, if it has no header.
+    manifest: Option<Manifest>,
+
     // Contains the contents of the script
     script: String,
 }
 
 /// This function splits a string into two pieces at the first
-/// instance of whitespace. If there is no white space in the string,
-/// The function returns Err(()).
-fn split_first_space<'a>(s: String) -> Result<(String, String), ()> {
-    let mut split_index = 0;
-    // Iterate over characters in the string and update split_index
-    for (i, c) in s.clone().chars().enumerate() {
-        split_index = i;
-
-        // If the character is whitespace, break
-        match c {
-            ' ' | '\t' | '\n' => break,
-            _ => {}
-        }
+/// instance of whitespace, and trims both of them. If there is no
+/// whitespace, the whole string is the first piece and the second is empty.
+/// If the string is empty or only whitespace, the function returns Err(()).
+/// 
+/// The string is split at a character boundary, so any UTF-8 input is safe.
+pub(crate) fn split_first_space(s: String) -> Result<(String, String), ()> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(())
     }
 
-    // If split_index is the last index in the string, return Err(())
-    if split_index >= s.to_string().len() - 1 {
-        return Err(())
+    match s.find(char::is_whitespace) {
+        Some(i) => Ok((s[..i].to_string(), s[i..].trim().to_string())),
+        None => Ok((s.to_string(), String::new()))
+    }
+}
+
+/// Get the line number of `line`, which must be a slice of `script`.
+pub(crate) fn line_number(script: &str, line: &str) -> usize {
+    let offset = (line.as_ptr() as usize).saturating_sub(script.as_ptr() as usize);
+    script[..offset.min(script.len())].matches('\n').count() + 1
+}
+
+/// The instructions that only download files into the package or install other
+/// packages. When several of these follow each other, they are run in parallel.
+const DOWNLOAD_INSTRUCTIONS: [&str; 3] = ["rapt-install", "fetch-archive", "fetch-binary"];
+
+/// This function returns whether the arguments of `command` are split into words,
+/// see tokenizer. Shell commands are read by the shell, and comments and the text
+/// of `echo` and `description` are kept as they are written, apostrophes and all.
+pub(crate) fn takes_words(command: &str) -> bool {
+    !matches!(command, "#" | "WINDOWS" | "MACOS" | "UBUNTU" | "LINUX" | "UNKNOWN" | "*" | "echo" | "description")
+}
+
+/// This function returns how to use an instruction that takes words as its arguments,
+/// like `chmod MODE PATH`, or None if `command` isn't one of them.
+pub(crate) fn usage(command: &str) -> Option<&'static str> {
+    match command {
+        "package" => Some("package NAME"),
+        "version" => Some("version VERSION"),
+        "description" => Some("description TEXT..."),
+        "platforms" => Some("platforms NAME..."),
+        "git-clone" => Some("git-clone URL [OPTIONS...]"),
+        "fetch-archive" => Some("fetch-archive URL [OPTIONS...]"),
+        "fetch-binary" => Some("fetch-binary URL [OPTIONS...]"),
+        "rapt-install" => Some("rapt-install URL"),
+        "backend-install" => Some("backend-install PACKAGE..."),
+        "write-hex" => Some("write-hex PATH HEX"),
+        "write-gzip" => Some("write-gzip PATH"),
+        "chmod" => Some("chmod MODE PATH"),
+        "mtime" => Some("mtime SECONDS PATH"),
+        "symlink" => Some("symlink PATH TARGET"),
+        "mkdir" => Some("mkdir PATH..."),
+        "echo" => Some("echo TEXT..."),
+        "add-path" => Some("add-path PATH..."),
+        "set-env" => Some("set-env NAME VALUE"),
+        "link-bin" => Some("link-bin PATH [as NAME]"),
+        _ => None
     }
-    // Otherwise, return the first and second half from the split index
-    let head = &s.as_str()[..split_index];
-    let tail = &s.as_str()[split_index..];
-    return Ok((head.trim().to_string(), tail.trim().to_string()))
 }
 
 /// This object represents an executable rapture script
 impl Script {
     /// This instantiates a new script with `script` as the script contents
     pub fn new<S: ToString>(script: S) -> Self {
-        return Self {
+        Self {
             package_name: None,
+            version: None,
+            description: None,
+            manifest: None,
             script: script.to_string(),
         }
     }
 
+    /// This function uses `manifest` as the script's manifest, like the `rapture.toml`
+    /// next to it. A manifest in the script's own header takes precedence.
+    pub fn with_manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// The text of the script.
+    pub fn contents(&self) -> &str {
+        &self.script
+    }
+
+    /// The instructions of the script, after its manifest header.
+    fn body(&self) -> &str {
+        split_header(&self.script).1
+    }
+
+    /// This function returns the manifest of the script, from its header or
+    /// from `with_manifest`. A script without either has an empty manifest.
+    pub fn manifest(&self) -> Result<Manifest, Error> {
+        match split_header(&self.script).0 {
+            Some(toml) => Manifest::parse(toml),
+            None => Ok(self.manifest.clone().unwrap_or_default())
+        }
+    }
+
+    /// The version of the package, from the manifest or the `version` instruction.
+    pub fn version(&self) -> Option<String> {
+        self.version.clone().or_else(|| self.manifest().ok()?.version)
+    }
+
+    /// The name of the package the script installs. This is found in the script
+    /// before it runs, so it is known even if the script fails before declaring it.
+    pub fn package_name(&self) -> Option<String> {
+        if self.package_name.is_some() {
+            return self.package_name.clone();
+        }
+        if let Some(name) = self.manifest().ok().and_then(|m| m.name) {
+            return Some(name);
+        }
+        self.body()
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("package "))
+            .map(|name| name.trim().to_string())
+            .find(|name| !name.is_empty())
+    }
+
+    /// This function returns every instruction in the script without running it,
+    /// in the order they would run. Comments and the contents of `write-gzip` blocks are skipped.
+    /// 
+    /// The dependencies in the manifest are listed first as `rapt-install` steps, and
+    /// its binaries last as `link-bin` steps, on the line the manifest starts.
+    pub fn steps(&self) -> Vec<Step> {
+        let manifest = self.manifest().unwrap_or_default();
+        let manifest_line = self.manifest_line();
+        let mut package = manifest.name.clone();
+        let mut steps: Vec<Step> = manifest.dependencies.iter()
+            .map(|url| Step { package: package.clone(), line: manifest_line, command: "rapt-install".to_string(), args: url.clone() })
+            .collect();
+
+        let mut lines = self.body().lines();
+        while let Some(line) = lines.next() {
+            if line.trim().is_empty() || line.trim().starts_with('#') {
+                continue;
+            }
+            let text = join_continued(line, &mut lines);
+            let (command, args) = match split_first_space(text) {
+                Ok((c, a)) => (c, a),
+                Err(_) => continue
+            };
+
+            if command == "package" {
+                package = Some(args.clone());
+            } else if command == "write-gzip" {
+                for data in lines.by_ref() {
+                    if data.trim() == "end-write" {
+                        break;
+                    }
+                }
+            }
+
+            steps.push(Step {
+                package: package.clone(),
+                line: line_number(&self.script, line),
+                command,
+                args,
+            });
+        }
+
+        steps.extend(manifest.binaries.iter().map(|path| {
+            Step { package: package.clone(), line: manifest_line, command: "link-bin".to_string(), args: path.clone() }
+        }));
+        steps
+    }
+
+    /// The line the manifest header starts on, or 0 if the manifest isn't in the script.
+    fn manifest_line(&self) -> usize {
+        match split_header(&self.script).0 {
+            Some(toml) => line_number(&self.script, toml) - 1,
+            None => 0
+        }
+    }
+
+    /// The description of the package, from the manifest or the `description` instruction.
+    pub fn description(&self) -> Option<String> {
+        self.description.clone().or_else(|| self.manifest().ok()?.description)
+    }
+
+    /// This function returns the platforms the package supports, declared in the
+    /// manifest and with `platforms` instructions, like `platforms linux macos`.
+    /// A script that doesn't declare any supports every platform.
+    pub fn platforms(&self) -> Result<Vec<String>, Error> {
+        let mut platforms = self.manifest()?.platforms;
+        for step in self.steps() {
+            if step.command == "platforms" {
+                platforms.extend(tokenize(&step.args)?);
+            }
+        }
+        match platforms.iter().find(|name| !PLATFORM_NAMES.contains(&name.as_str())) {
+            Some(name) => Err(Error::parse(format!("Unknown platform '{}', expected one of {}", name, PLATFORM_NAMES.join(", ")))),
+            None => Ok(platforms)
+        }
+    }
+
+    /// This function fails with an unsupported platform error if the package
+    /// doesn't support the operating system it is being installed on.
+    /// With `rapt install --ignore-platform`, it only says so and lets the install go on.
+    fn check_platform(&self) -> Result<(), Error> {
+        let platforms = self.platforms()?;
+        let platform = Platform::get().os_name();
+        if platforms.is_empty() || platforms.iter().any(|name| name == platform) {
+            return Ok(());
+        }
+
+        let error = Error::unsupported_platform(self.package_name(), platform, platforms);
+        if ignore_platform() {
+            observer().message(&format!("{}, installing it anyway", error));
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
     /// Runs a given command on the proper operating system's shell.
     /// If the script has a package declaration, call the command
     /// within the package installation directory.
@@ -75,7 +272,12 @@ impl Script {
     /// 
     /// If I dont declare the package name before running a command,
     /// the exact command you gave this function is executed instead.
-    pub fn command<S: Display>(&self, cmd: S) -> Result<(), String> {
+    /// 
+    /// Shell commands often run the system package manager, like `sudo apt update`,
+    /// and there's no way to tell which ones do. So only one shell command runs at a
+    /// time, even when dependencies are installed in parallel, see jobs::lock_package_manager.
+    pub fn command<S: Display>(&self, cmd: S) -> Result<(), Error> {
+        let _lock = lock_package_manager();
         match self.package_name.clone() {
             Some(name) => {
                 Platform::command(format!("cd {}; {};", PathManager::package_dir(name.to_string()), cmd))?;
@@ -87,192 +289,378 @@ impl Script {
         Ok(())
     }
 
+    /// This function returns the absolute path of `path` within the package
+    /// installation directory. If the script hasn't declared a package yet, this
+    /// returns an error saying that it tried to `action` without one.
+    /// 
+    /// Paths that could point outside of the package installation directory are
+    /// rejected, see PathManager::safe_join.
+    fn package_path(&self, path: &str, action: &str) -> Result<PathBuf, Error> {
+        match self.package_name.clone() {
+            Some(name) => match PathManager::safe_join(PathManager::package_dir(name), path) {
+                Ok(absolute_path) => Ok(absolute_path),
+                Err(Error::Permission(e)) => Err(Error::permission(format!("Tried to {} at an unsafe path: {}", action, e))),
+                Err(e) => Err(e)
+            },
+            None => Err(Error::parse(format!("Tried to {} without declaring the install script as a package installer via the `package PACKAGE_NAME` rapture command.", action)))
+        }
+    }
+
+    /// This function runs a single download instruction. These are the
+    /// instructions that can run at the same time as each other.
+    fn download_step(&self, command: &str, args: &str) -> Result<(), Error> {
+        match command {
+            // Download an archive and extract it into the package, for example:
+            // `fetch-archive https://example.com/tool-1.0.tar.gz into tool sha256=... strip=1`
+            // 
+            // .tar.gz, .tar.xz, .tar.zst, .tar, and .zip archives are supported.
+            // fetch-archive can only be used after the package declaration.
+            "fetch-archive" => {
+                let (url, options) = ArchiveOptions::parse(args)?;
+                let into = options.into.clone().unwrap_or_default();
+                let destination = self.package_path(&into, "extract an archive")?;
+                Downloader::download_archive(url, destination, &options)
+            },
+            // Download the prebuilt binary for the current platform, for example:
+            // `fetch-binary https://example.com/tool-{os}-{arch}.{ext} os.macos=darwin bin=tool`
+            // 
+            // See download::BinaryOptions for every option.
+            // fetch-binary can only be used after the package declaration.
+            "fetch-binary" => {
+                let (template, options) = BinaryOptions::parse(args)?;
+                let into = options.archive.into.clone().unwrap_or_default();
+                let destination = self.package_path(&into, "download a binary")?;
+                Downloader::download_binary(template, destination, &options)
+            },
+            // Download a rapture script from url and install it before continuing.
+            "rapt-install" => match tokenize(args)?.as_slice() {
+                [url] => install(url.to_string()).map_err(|e| Error::dependency(url, e)),
+                _ => Err(Error::parse(format!("Expected 'rapt-install URL', found 'rapt-install {}'", args)))
+            },
+            _ => Err(Error::parse(format!("'{}' is not a download instruction", command)))
+        }
+    }
+
     /// This function runs the rapture script. It iterates over the lines in the
     /// script, and matches the commands and the arguments. I would like to change
     /// this in the future, replacing it with an embeddable scripting language.
-    pub fn run(&mut self) -> Result<(), String> {
+    pub fn run(&mut self) -> Result<(), Error> {
+        // A package that doesn't support this platform fails before anything is installed
+        self.check_platform()?;
+
+        // The manifest declares the package and installs its dependencies before any
+        // instruction runs, like `package` and `rapt-install` lines at the top of the script
+        let manifest = self.manifest()?;
+        let manifest_line = self.manifest_line();
+        if let Some(name) = &manifest.name {
+            self.declare_package(name)?;
+        }
+        let dependencies: Vec<Step> = manifest.dependencies.iter()
+            .map(|url| Step { package: self.package_name.clone(), line: manifest_line, command: "rapt-install".to_string(), args: url.clone() })
+            .collect();
+        self.download_steps(&dependencies)?;
+
         // The iterator for the lines in the script.
-        let lines = self.script.lines();
+        // Some instructions, like `write-gzip`, read the lines that follow them.
+        let script = self.script.clone();
+        let mut lines = split_header(&script).1.lines();
+
+        while let Some(line) = lines.next() {
+            // Comments are skipped, even without a space after the `#`, like `#comment`
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            // A line ending with a backslash continues on the next line
+            let text = join_continued(line, &mut lines);
 
-        for line in lines {
             // Split each line by the whitespace.
             // The first string before the whitespace will be the command,
             // and the second string will be the argument.
             // 
-            // If split_first_space cant find a space, it will return Err,
+            // If the line is empty, split_first_space will return Err,
             // and this will skip that line and try to parse the next.
-            let (command, args) = match split_first_space(line.to_string()) {
+            let (command, args) = match split_first_space(text.clone()) {
                 Ok((c, a)) => (c, a),
                 Err(_) => continue
             };
 
-            // Match the command and args as a tuple of &str
-            match (command.as_str(), args.as_str()) {
-                // The current instruction is a package declaration.
-                // First, we create the directory where the package contents
-                // will be installed.
-                // 
-                // Then we add the directory to the user's path,
-                // and give the running script the package name to use in future commands.
-                ("package", name) => {
-                    PathManager::make_package_dir(name.to_string())?;
-                    PathManager::add_to_path(name.to_string())?;
-                    self.package_name = Some(name.to_string());
-                },
-                // Clone a git repository into the current package.
-                // If the current script is not a package installer, throw an error.
-                ("git-clone", url) => {
-                    match self.package_name.clone() {
-                        Some(name) => {
-                            gitclone(name, url.trim_start_matches("\"")
-                                                .trim_start_matches("'")
-                                                .trim_end_matches("\"")
-                                                .trim_end_matches("'")
-                                                .to_string())?;
-                        },
-                        None => {
-                            return Err("Tried to clone repository into package install directory without declaring the install script as a package installer via the `package PACKAGE_NAME` rapture command.".to_string())
-                        }
-                    }
-                },
-                // Download a rapture script from url and install it before continuing.
-                ("rapt-install", url) => {
-                    install(url.to_string())?;
-                },
-                // Call the operating system's native package manager.
-                ("backend-install", package) => {
-                    backup::install(package.to_string())?;
-                },
-                // This is mainly a feature of the `capture` subcommand.
-                // This is not meant for users to be messing around with.
-                ("write-hex", path_hex) => {
-                    match self.package_name.clone() {
-                        Some(name) => {
-                            // Split the argments into a path and the bytes to write
-                            let (path, bytes) = match split_first_space(path_hex.to_string()) {
-                                Ok((c, a)) => (c, a),
-                                Err(_) => continue
-                            };
-                            
-                            // Get the path relative to the package install directory
-                            let package_dir = PathManager::package_dir(name.to_string());
-                            let mut absolute_path = PathBuf::new();
-                            absolute_path.push(package_dir);
-                            absolute_path.push(path);
-                            
-                            // Open the file for writing
-                            let mut file = match OpenOptions::new()
-                                .create(true)
-                                .write(true)
-                                .open(absolute_path.clone()) 
-                            {
-                                Ok(f) => Ok(f),
-                                Err(_) => Err(format!("Could not open file '{}'", path_to_string(absolute_path.clone())))
-                            }?;
-
-                            // Decode the hex string into a list of Vec<u8>.
-                            // These are not UTF-8 characters!!! These are the 
-                            // bytes to write directly to the opened file.
-                            match decode(bytes.clone()) {
-                                Ok(vector) => match file.write_all(&vector) {
-                                    Ok(_) => {},
-                                    Err(_) => return Err(format!("Could not write decoded bytes to file '{}'", path_to_string(absolute_path)))
-                                },
-                                Err(_) => return Err(format!("Could not decode hex code '{}'", bytes.clone()))
-                            };
-                        },
-                        None => {
-                            return Err("Tried to write hex to a file without declaring the install script as a package installer via the `package PACKAGE_NAME` rapture command.".to_string())
-                        }
-                    }
-                },
-                // Make a directory.
-                // This can be a directory that has non-existant parent directories.
-                // For example, if I invoke the rapture command:
-                // `mkdir ./cmake/contrib/profiling`
-                // Rapture will create each of the parent directories if they do not already exist.
-                ("mkdir", path) => {
-                    match self.package_name.clone() {
-                        Some(name) => {
-                            // Make the path a relative path to the package install directory
-                            let package_dir = PathManager::package_dir(name.to_string());
-                            let mut absolute_path = PathBuf::new();
-                            absolute_path.push(package_dir);
-                            absolute_path.push(path);
-
-                            // Create the folder using create_dir_all.
-                            // create_dir_all creates parent directories as needed,
-                            // similar to mkdir -p DIRECTORY
-                            match create_dir_all(absolute_path.clone()) {
-                                Ok(()) => {},
-                                Err(_) => return Err(format!("Failed to create directory {}", path_to_string(absolute_path)))
-                            }
-                        },
-                        None => {
-                            return Err("Tried to make directory without declaring the install script as a package installer via the `package PACKAGE_NAME` rapture command.".to_string())
-                        }
-                    }
-                },
-                // This prints a message to the console
-                ("echo", string) => {
-                    println!("{}", string);
-                },
-                // This command adds a path to the users path.
-                // This is mainly used if there is a `bin` directory or another directory
-                // within the package install directory that needs to be added to the path.
-                // 
-                // add-path can only be used after the package declaration.
-                ("add-path", path) => {
-                    match self.package_name.clone() {
-                        Some(name) => {
-                            // Get the path relative to the package dir
-                            let package_dir = PathManager::package_dir(name.to_string());
-                            let mut absolute_path = PathBuf::new();
-                            absolute_path.push(package_dir);
-                            absolute_path.push(path);
-
-                            // Call frontend::add_to_path
-                            add_to_path(path_to_string(absolute_path))?;
-                        },
-                        None => {
-                            return Err("Tried to add to path without declaring the install script as a package installer via the `package PACKAGE_NAME` rapture command.".to_string())
-                        }
+            // Downloads report each of their steps themselves, see run_step
+            if DOWNLOAD_INSTRUCTIONS.contains(&command.as_str()) {
+                match self.run_step(&script, line, &command, &args, &mut lines) {
+                    Ok(()) => {},
+                    Err(e @ Error::Script { .. }) => return Err(e),
+                    // The line couldn't be read, so it never became a download step
+                    Err(e) => return Err(Error::script(self.package_name.clone(), line_number(&script, line), text.trim(), e)),
+                }
+                continue;
+            }
+
+            let step = self.step(&script, line, &command, &args);
+            observer().step_started(&step);
+            let result = self.run_step(&script, line, &command, &args, &mut lines);
+            observer().step_finished(&step, &result);
+            if let Err(e) = result {
+                return Err(Error::script(step.package, step.line, text.trim(), e));
+            }
+        }
+
+        // Link the binaries the manifest provides, now that they are installed
+        for path in &manifest.binaries {
+            let step = Step { package: self.package_name.clone(), line: manifest_line, command: "link-bin".to_string(), args: path.clone() };
+            observer().step_started(&step);
+            let result = match self.package_name.clone() {
+                Some(name) => PathManager::link_bin(name, path.clone(), None),
+                None => Err(Error::parse("The manifest lists binaries, but the script doesn't declare a package to find them in"))
+            };
+            observer().step_finished(&step, &result);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// This function creates the installation directory of the package `name`,
+    /// and makes the following instructions work inside of it.
+    fn declare_package(&mut self, name: &str) -> Result<(), Error> {
+        PathManager::make_package_dir(name.to_string())?;
+        PathManager::make_bin_dir()?;
+        self.package_name = Some(name.to_string());
+        Ok(())
+    }
+
+    /// This function runs download steps at the same time, see jobs::run_parallel.
+    /// Each step reports itself to the observer, and the first error is returned.
+    fn download_steps(&self, steps: &[Step]) -> Result<(), Error> {
+        let results = run_parallel(steps, |step| {
+            observer().step_started(step);
+            let result = self.download_step(&step.command, &step.args);
+            observer().step_finished(step, &result);
+            result.map_err(|e| {
+                Error::script(step.package.clone(), step.line, format!("{} {}", step.command, step.args), e)
+            })
+        });
+        for result in results {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// This function describes the instruction on `line` of `script` for the observer.
+    fn step(&self, script: &str, line: &str, command: &str, args: &str) -> Step {
+        Step {
+            package: self.package_name.clone(),
+            line: line_number(script, line),
+            command: command.to_string(),
+            args: args.to_string(),
+        }
+    }
+
+    /// This function runs a single instruction, found on `line` of `script`.
+    /// Instructions that span several lines, like `write-gzip`, read the rest
+    /// of their lines from `lines`.
+    fn run_step(&mut self, script: &str, line: &str, command: &str, args: &str, lines: &mut Lines) -> Result<(), Error> {
+        // The arguments of most instructions are split into words, see takes_words
+        let words = if takes_words(command) {
+            tokenize(args)?
+        } else {
+            vec![]
+        };
+
+        // Match the command and its words
+        match (command, words.as_slice()) {
+            // The current instruction is a package declaration.
+            // First, we create the directory where the package contents
+            // will be installed.
+            // 
+            // Then we make sure the bin directory is on the user's path,
+            // and give the running script the package name to use in future commands.
+            // The package's binaries are made available with `link-bin`.
+            ("package", [name]) => {
+                if let Some(declared) = self.manifest()?.name {
+                    if &declared != name {
+                        return Err(Error::parse(format!("The manifest names the package '{}', but the script declares '{}'", declared, name)));
                     }
-                },
-                // The hastag symbol denotes a comment
-                ("#", _) => {},
-
-                // The following commands run `arg` as a shell
-                // command on their respective operating systems.
-                ("WINDOWS", cmd) => {
-                    if Platform::get() == Platform::Windows {
-                        self.command(cmd)?;
+                }
+                self.declare_package(name)?;
+            },
+            // Describe the package. These don't do anything during the install,
+            // they're information for the people reading and using the script.
+            ("version", [version]) => {
+                self.version = Some(version.to_string());
+            },
+            ("description", _) => {
+                self.description = Some(args.trim().to_string());
+            },
+            // The supported platforms were checked before the script started, see check_platform
+            ("platforms", [_, ..]) => {},
+            // Clone a git repository into the current package, for example:
+            // `git-clone https://github.com/user/repo into src tag=v1.0 depth=1 submodules`
+            // 
+            // If the repository was already cloned, it is updated instead.
+            // If the current script is not a package installer, throw an error.
+            ("git-clone", _) => {
+                let (url, options) = CloneOptions::parse(args)?;
+                let into = match options.into.clone() {
+                    Some(into) => into,
+                    None => repository_name(&url)?
+                };
+                let destination = self.package_path(&into, "clone a repository")?;
+                gitclone_with(url, destination, &options)?;
+            },
+            // Downloads don't depend on each other, so a run of them is done in parallel.
+            // See download_step for each instruction.
+            (command, _) if DOWNLOAD_INSTRUCTIONS.contains(&command) => {
+                let mut steps = vec![self.step(script, line, command, args)];
+                // Look ahead for more downloads, skipping empty lines and comments
+                let mut rest = lines.clone();
+                while let Some(next) = rest.next() {
+                    if next.trim().is_empty() || next.trim().starts_with('#') {
+                        continue;
                     }
-                },
-                ("MACOS", cmd) => {
-                    if Platform::get() == Platform::MacOS {
-                        self.command(cmd)?;
+                    match split_first_space(join_continued(next, &mut rest)) {
+                        Ok((c, a)) if DOWNLOAD_INSTRUCTIONS.contains(&c.as_str()) => steps.push(self.step(script, next, &c, &a)),
+                        _ => break
                     }
-                },
-                ("UBUNTU", cmd) | ("LINUX", cmd) => {
-                    if Platform::get() == Platform::Ubuntu {
-                        self.command(cmd)?;
+                    *lines = rest.clone();
+                }
+
+                self.download_steps(&steps)?;
+            },
+            // Call the operating system's native package manager.
+            ("backend-install", packages) if !packages.is_empty() => {
+                backup::install(packages.join(" "))?;
+            },
+            // This is how older versions of the `capture` subcommand stored files.
+            // This is not meant for users to be messing around with.
+            ("write-hex", [path, bytes]) => {
+                // Get the path relative to the package install directory,
+                // and write the decoded bytes to it
+                write_hex(self.package_path(path, "write hex to a file")?, bytes.to_string())?;
+            },
+            // An empty file was captured as `write-hex PATH ` with nothing after the path
+            ("write-hex", [path]) => {
+                write_hex(self.package_path(path, "write hex to a file")?, String::new())?;
+            },
+            // Write a file compressed with gzip. This is what the `capture`
+            // subcommand generates. The compressed contents follow on `data` lines,
+            // and the file ends with an `end-write` line.
+            ("write-gzip", [path]) => {
+                write_gzip(self.package_path(path, "write a file")?, lines)?;
+            },
+            // Restore the permissions of a file, for example `chmod 755 bin/tool`.
+            // This does nothing on platforms without unix permissions.
+            ("chmod", [mode, path]) => {
+                set_mode(self.package_path(path, "change permissions")?, mode)?;
+            },
+            // Restore the modification time of a file, in seconds since the unix epoch.
+            // For example: `mtime 1571234567 bin/tool`.
+            ("mtime", [time, path]) => {
+                set_mtime(self.package_path(path, "change a modification time")?, time)?;
+            },
+            // Create a symlink inside the package, for example `symlink lib/libfoo.so libfoo.so.1`
+            // creates `lib/libfoo.so` pointing to `libfoo.so.1`.
+            ("symlink", [path, target]) => {
+                // The link itself is replaced, so only the directory it is created in
+                // has to be inside the package. Whatever it points to is checked when
+                // another instruction writes through it.
+                let (parent, file_name) = match (Path::new(path).parent(), Path::new(path).file_name()) {
+                    (Some(parent), Some(file_name)) => (parent.to_string_lossy().to_string(), file_name.to_os_string()),
+                    _ => return Err(Error::parse(format!("Invalid symlink path '{}'", path)))
+                };
+                make_symlink(self.package_path(&parent, "create a symlink")?.join(file_name), target)?
+            },
+            // Make directories.
+            // These can be directories that have non-existant parent directories.
+            // For example, if I invoke the rapture command:
+            // `mkdir ./cmake/contrib/profiling "My Documents"`
+            // Rapture will create each of the parent directories if they do not already exist.
+            ("mkdir", paths) if !paths.is_empty() => {
+                for path in paths {
+                    // Make the path a relative path to the package install directory
+                    let absolute_path = self.package_path(path, "make directory")?;
+
+                    // Create the folder using create_dir_all.
+                    // create_dir_all creates parent directories as needed,
+                    // similar to mkdir -p DIRECTORY
+                    match create_dir_all(absolute_path.clone()) {
+                        Ok(()) => {},
+                        Err(e) => return Err(Error::io(format!("Failed to create directory {}", path_to_string(absolute_path)), e))
                     }
-                },
-                ("UNKNOWN", cmd) => {
-                    if Platform::get() == Platform::Unknown {
-                        self.command(cmd)?;
+                }
+            },
+            // This prints a message to the console
+            ("echo", _) => {
+                observer().message(args.trim());
+            },
+            // This command adds paths to the users path.
+            // This is mainly used if there is a `bin` directory or another directory
+            // within the package install directory that needs to be added to the path.
+            // 
+            // add-path can only be used after the package declaration.
+            ("add-path", paths) if !paths.is_empty() => {
+                for path in paths {
+                    // Get the path relative to the package dir
+                    let absolute_path = self.package_path(path, "add to path")?;
+
+                    // Call frontend::add_to_path
+                    add_to_path(path_to_string(absolute_path))?;
+                }
+            },
+            // This command sets an environment variable in the generated
+            // environment files, for example `set-env GOPATH /home/me/go`.
+            // The variable is available in every new shell after the install.
+            ("set-env", [name, value @ ..]) if !value.is_empty() => {
+                EnvManager::set_var(name.to_string(), value.join(" "))?;
+            },
+            // This command links a binary from the package install directory into
+            // `~/.rapture/bin`, which is the only directory rapture keeps on the path.
+            // For example: `link-bin build/tool` or `link-bin build/tool as other-name`.
+            // 
+            // link-bin can only be used after the package declaration.
+            ("link-bin", [path, rest @ ..]) if rest.is_empty() || (rest.len() == 2 && rest[0] == "as") => {
+                match self.package_name.clone() {
+                    Some(name) => PathManager::link_bin(name, path.to_string(), rest.get(1).cloned())?,
+                    None => {
+                        return Err(Error::parse("Tried to link a binary without declaring the install script as a package installer via the `package PACKAGE_NAME` rapture command."))
                     }
-                },
-                // Runs a command on all operating systems
-                ("*", cmd) => {
-                    self.command(cmd)?;
-                },
-                ("", "") => {},
-                // An unrecognized command was given, return Err
-                (command, args) => {
-                    return Err(format!("Unrecognized command '{} {}'", command, args));
                 }
+            },
+            // The hastag symbol denotes a comment
+            ("#", _) => {},
+
+            // The following commands run `arg` as a shell
+            // command on their respective operating systems.
+            ("WINDOWS", _) => {
+                if Platform::get() == Platform::Windows {
+                    self.command(args)?;
+                }
+            },
+            ("MACOS", _) => {
+                if Platform::get() == Platform::MacOS {
+                    self.command(args)?;
+                }
+            },
+            ("UBUNTU", _) | ("LINUX", _) => {
+                if Platform::get() == Platform::Ubuntu {
+                    self.command(args)?;
+                }
+            },
+            ("UNKNOWN", _) => {
+                if Platform::get() == Platform::Unknown {
+                    self.command(args)?;
+                }
+            },
+            // Runs a command on all operating systems
+            ("*", _) => {
+                self.command(args)?;
+            },
+            // A known instruction was given the wrong arguments
+            (command, _) if usage(command).is_some() => {
+                return Err(Error::parse(format!("Expected '{}', found '{}'", usage(command).unwrap_or_default(), format!("{} {}", command, args).trim())));
+            },
+            // An unrecognized command was given, return Err
+            (command, _) => {
+                return Err(Error::parse(format!("Unrecognized command '{}'", format!("{} {}", command, args).trim())));
             }
         }
         Ok(())
@@ -281,7 +669,85 @@ impl Script {
 
 // Dummy Display impl used for debugging
 impl Display for Script {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.script)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::temp_home;
+    use crate::platform::set_ignore_platform;
+
+    #[test]
+    fn write_hex_without_hex_writes_an_empty_file() {
+        let (_guard, home) = temp_home();
+        Script::new("package dragon\nwrite-hex empty \nwrite-hex hi 6869\n").run().unwrap();
+        let package = home.path().join(".rapture").join("dragon");
+        assert_eq!(std::fs::read(package.join("empty")).unwrap(), b"");
+        assert_eq!(std::fs::read(package.join("hi")).unwrap(), b"hi");
+    }
+
+    /// The name of the platform the tests run on, and of one they don't.
+    fn platforms() -> (&'static str, &'static str) {
+        let current = Platform::get().os_name();
+        let other = if current == "windows" { "linux" } else { "windows" };
+        (current, other)
+    }
+
+    #[test]
+    fn unsupported_platforms_fail_before_anything_runs() {
+        let (_guard, home) = temp_home();
+        let (_, other) = platforms();
+        let script = format!("package dragon\nplatforms {}\nwrite-hex a 6869\n", other);
+        match Script::new(&script).run() {
+            Err(e @ Error::UnsupportedPlatform { .. }) => {
+                assert_eq!(e.exit_code(), 8);
+                assert!(e.to_string().contains(other), "{}", e);
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(!home.path().join(".rapture").join("dragon").exists());
+    }
+
+    #[test]
+    fn ignoring_the_platform_installs_anyway() {
+        let (_guard, home) = temp_home();
+        let (_, other) = platforms();
+        set_ignore_platform(true);
+        let result = Script::new(format!("package dragon\nplatforms {}\nwrite-hex a 6869\n", other)).run();
+        set_ignore_platform(false);
+        result.unwrap();
+        assert!(home.path().join(".rapture").join("dragon").join("a").exists());
+    }
+
+    #[test]
+    fn platforms_from_the_manifest_and_the_script_are_combined() {
+        // Another test might be ignoring the platform
+        let (_guard, _home) = temp_home();
+        let (current, other) = platforms();
+        let supports = |script: String| Script::new(script).check_platform().is_ok();
+
+        assert!(supports("mkdir a\n".to_string()));
+        assert!(supports(format!("platforms {} {}\n", other, current)));
+        assert!(!supports(format!("+++\nplatforms = [\"{}\"]\n+++\nmkdir a\n", other)));
+        assert!(supports(format!("+++\nplatforms = [\"{}\"]\n+++\nplatforms {}\n", other, current)));
+        assert!(supports(format!("+++\nplatforms = [\"{}\"]\n+++\nplatforms {}\n", current, other)));
+        let manifest = Manifest { platforms: vec![other.to_string()], ..Manifest::default() };
+        assert!(Script::new("mkdir a\n").with_manifest(manifest).check_platform().is_err());
+        assert!(matches!(Script::new("platforms beos\n").check_platform(), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn unreadable_downloads_report_their_line() {
+        let (_guard, _home) = temp_home();
+        match Script::new("package dragon\n\nrapt-install \"file:///x\n").run() {
+            Err(Error::Script { line, source, .. }) => {
+                assert_eq!(line, 3);
+                assert!(matches!(*source, Error::Parse(_)), "{}", source);
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+/// This part of the crate splits the arguments of an instruction into words, the way
+/// a shell does. Words are separated by whitespace, and can be quoted to include it:
+///
+/// ```text
+/// mkdir "My Documents"
+/// symlink 'lib/libfoo.so' libfoo.so.1
+/// echo Installing\ the\ tool
+/// ```
+///
+/// Inside single quotes every character is kept as it is. Inside double quotes a
+/// backslash only escapes `"` and another backslash. Outside of quotes a backslash
+/// escapes any character. A line ending with a backslash continues on the next line.
+///
+/// Shell commands, like `* make install`, are not split, the shell reads them itself.
+/// Neither is the text of `echo` and `description`, so `echo it's done` works.
+use crate::error::Error;
+
+/// This function splits `text` into words, removing the quotes and escapes around them.
+pub fn tokenize(text: &str) -> Result<Vec<String>, Error> {
+    let mut words = vec![];
+    let mut word = String::new();
+    // Quotes can make an empty word, like `""`, so track if there is one
+    let mut in_word = false;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(Error::parse(format!("Missing the closing ' in '{}'", text)))
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            },
+                            None => return Err(Error::parse(format!("Missing the closing \" in '{}'", text)))
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(Error::parse(format!("Missing the closing \" in '{}'", text)))
+                    }
+                }
+            },
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some(c) => word.push(c),
+                    None => return Err(Error::parse(format!("Nothing to escape after the \\ at the end of '{}'", text)))
+                }
+            },
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// This function quotes `word` so that `tokenize` reads it back as a single word.
+/// Words without whitespace, quotes, or other unusual characters are left as they are.
+pub fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word.chars().all(|c| c.is_alphanumeric() || "-_./:=@%+,{}~*?&#".contains(c))
+        && !word.starts_with('#');
+    if plain {
+        return word.to_string();
+    }
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Check if a line ends with a backslash that isn't escaped by another one.
+pub fn is_continued(line: &str) -> bool {
+    let backslashes = line.trim_end().chars().rev().take_while(|c| *c == '\\').count();
+    backslashes % 2 == 1
+}
+
+/// This function returns `line`, joined with the lines after it from `lines`
+/// for as long as it ends with a backslash. Comments are never continued.
+pub fn join_continued<'a, I: Iterator<Item = &'a str>>(line: &'a str, lines: &mut I) -> String {
+    let mut joined = line.to_string();
+    if line.trim_start().starts_with('#') {
+        return joined;
+    }
+
+    // A backslash on the last line has nothing to continue onto, so it's kept
+    // and reported by the tokenizer
+    while is_continued(&joined) {
+        match lines.next() {
+            Some(next) => {
+                joined.truncate(joined.trim_end().len() - 1);
+                joined.push(' ');
+                joined.push_str(next.trim_start());
+            },
+            None => break
+        }
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        tokenize(text).unwrap()
+    }
+
+    fn joined(text: &str) -> Vec<String> {
+        let mut lines = text.lines();
+        let mut result = vec![];
+        while let Some(line) = lines.next() {
+            result.push(join_continued(line, &mut lines));
+        }
+        result
+    }
+
+    #[test]
+    fn words_are_split_by_whitespace() {
+        assert_eq!(words("  a\tb   c  "), vec!["a", "b", "c"]);
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn single_quotes_keep_every_character() {
+        assert_eq!(words(r#"'My Documents' 'a\b"c' x'y z'"#), vec!["My Documents", r#"a\b"c"#, "xy z"]);
+    }
+
+    #[test]
+    fn double_quotes_only_escape_quotes_and_backslashes() {
+        assert_eq!(words(r#""say \"hi\"" "a\\b" "c\d" "it's""#), vec![r#"say "hi""#, r"a\b", r"c\d", "it's"]);
+    }
+
+    #[test]
+    fn backslashes_escape_any_character_outside_quotes() {
+        assert_eq!(words(r"Installing\ the\ tool \'a\' \\ \x"), vec!["Installing the tool", "'a'", r"\", "x"]);
+    }
+
+    #[test]
+    fn empty_quotes_make_empty_words() {
+        assert_eq!(words(r#"a "" '' b"#), vec!["a", "", "", "b"]);
+        assert_eq!(words(r#""""#), vec![""]);
+    }
+
+    #[test]
+    fn unterminated_input_is_an_error() {
+        for (text, message) in [("'abc", "closing '"), (r#""abc"#, "closing \""), (r#""abc\"#, "closing \""), (r"abc\", "Nothing to escape")] {
+            match tokenize(text) {
+                Err(Error::Parse(m)) => assert!(m.contains(message), "{}: {}", text, m),
+                other => panic!("{}: {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn quoted_words_are_read_back() {
+        for word in ["plain", "", "two words", "it's", r#"say "hi""#, r"C:\Program Files\", "#hash", "tab\there", "ünï"] {
+            assert_eq!(words(&quote(word)), vec![word], "{}", quote(word));
+        }
+        assert_eq!(quote("https://example.com/a.tar.gz?x=1&y=2"), "https://example.com/a.tar.gz?x=1&y=2");
+        assert_eq!(quote("two words"), "\"two words\"");
+    }
+
+    #[test]
+    fn escaped_backslashes_dont_continue_lines() {
+        assert!(is_continued("a \\"));
+        assert!(is_continued("a \\  "));
+        assert!(!is_continued("a \\\\"));
+        assert!(is_continued("a \\\\\\"));
+        assert!(!is_continued("a"));
+    }
+
+    #[test]
+    fn lines_are_joined_with_the_next_line() {
+        assert_eq!(joined("a \\\n    b \\\n  c\nd"), vec!["a  b  c", "d"]);
+        assert_eq!(joined("a \\\\\nb"), vec!["a \\\\", "b"]);
+    }
+
+    #[test]
+    fn a_backslash_on_the_last_line_is_kept() {
+        assert_eq!(joined("a\nb \\"), vec!["a", "b \\"]);
+        assert!(tokenize(&joined("b \\")[0]).is_err());
+    }
+
+    #[test]
+    fn comments_are_never_continued() {
+        assert_eq!(joined("# a \\\nb"), vec!["# a \\", "b"]);
+        assert_eq!(joined("  #a\\\nb"), vec!["  #a\\", "b"]);
+    }
+}
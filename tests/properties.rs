@@ -0,0 +1,107 @@
+//! Property tests for everything that reads a script. Scripts are downloaded from
+//! anywhere, so reading one must never panic, whatever it contains.
+use proptest::prelude::*;
+use rapture::capture::{decode, encode, list};
+use rapture::check::check;
+use rapture::format::format_script;
+use rapture::manifest::split_header;
+use rapture::tokenizer::{quote, tokenize};
+use rapture::Script;
+
+/// A word that looks like the arguments scripts use, with the characters
+/// the tokenizer treats specially.
+fn word() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-z0-9./_-]{1,8}",
+        "[ a-z'\"\\\\#$]{0,6}",
+        any::<String>(),
+    ]
+}
+
+/// A line that looks like it belongs in a script.
+fn line() -> impl Strategy<Value = String> {
+    let instruction = prop_oneof![
+        Just("package"), Just("version"), Just("description"), Just("platforms"),
+        Just("git-clone"), Just("fetch-archive"), Just("fetch-binary"), Just("rapt-install"),
+        Just("backend-install"), Just("write-hex"), Just("write-gzip"), Just("chmod"),
+        Just("mtime"), Just("symlink"), Just("mkdir"), Just("echo"), Just("add-path"),
+        Just("set-env"), Just("link-bin"), Just("#"), Just("#note"), Just("LINUX"),
+        Just("*"), Just("data"), Just("end-write"), Just("+++"), Just("name ="), Just(""),
+    ];
+    (instruction, prop::collection::vec(word(), 0..4), any::<bool>())
+        .prop_map(|(instruction, words, continued)| {
+            let mut line = format!("{} {}", instruction, words.join(" "));
+            if continued {
+                line.push('\\');
+            }
+            line
+        })
+}
+
+/// A script made of lines that look like they belong in one.
+fn script() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop::collection::vec(line(), 0..12).prop_map(|lines| lines.join("\n")),
+        any::<String>(),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn tokenize_never_panics(text in any::<String>()) {
+        let _ = tokenize(&text);
+    }
+
+    #[test]
+    fn quoted_words_are_tokenized_back(words in prop::collection::vec(word(), 0..6)) {
+        let line: Vec<String> = words.iter().map(|w| quote(w)).collect();
+        prop_assert_eq!(tokenize(&line.join(" ")).unwrap(), words);
+    }
+
+    #[test]
+    fn split_header_returns_parts_of_the_script(script in script()) {
+        let (header, body) = split_header(&script);
+        prop_assert!(script.ends_with(body));
+        match header {
+            Some(toml) => prop_assert!(script.contains(toml)),
+            None => prop_assert_eq!(body, script.as_str()),
+        }
+    }
+
+    #[test]
+    fn reading_a_script_never_panics(text in script()) {
+        let script = Script::new(text.clone());
+        let lines = text.lines().count();
+        for step in script.steps() {
+            prop_assert!(step.line <= lines, "step on line {} of {}", step.line, lines);
+        }
+        let _ = script.package_name();
+        let _ = script.manifest();
+        let _ = script.platforms();
+        let _ = list(&text);
+    }
+
+    #[test]
+    fn checking_a_script_never_panics(text in script()) {
+        let lines = text.lines().count();
+        for diagnostic in check(&text) {
+            prop_assert!(diagnostic.line <= lines, "{} of {}", diagnostic, lines);
+        }
+    }
+
+    #[test]
+    fn formatting_is_idempotent(text in script()) {
+        let formatted = format_script(&text);
+        prop_assert_eq!(format_script(&formatted), formatted);
+    }
+
+    #[test]
+    fn decoding_hex_never_panics(hex in any::<String>()) {
+        let _ = decode(hex);
+    }
+
+    #[test]
+    fn hex_is_decoded_back(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        prop_assert_eq!(decode(encode(bytes.clone()).unwrap()).unwrap(), bytes);
+    }
+}